@@ -0,0 +1,103 @@
+use criterion::{criterion_group, BenchmarkId, Criterion};
+use ondc_websocket::broker::{Destination, MessageBroker, MessageData};
+use ondc_websocket::pulsar_client::{PulsarBroker, PulsarClient};
+use pulsar::{producer::ProducerOptions, Pulsar, SubType, TokioExecutor};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+const PUBLISHES: usize = 256;
+const CONCURRENT_REQUESTS: usize = 64;
+
+fn pulsar_url() -> String {
+    std::env::var("BENCH_PULSAR_URL").unwrap_or_else(|_| "pulsar://127.0.0.1:6650".to_string())
+}
+
+async fn broker(pool_size: usize, batching: bool) -> Result<PulsarBroker, anyhow::Error> {
+    let client = PulsarClient::new(
+        Pulsar::builder(pulsar_url(), TokioExecutor),
+        "public".to_string(),
+        "default".to_string(),
+        format!("bench-{}", uuid::Uuid::new_v4()),
+    )
+    .await?;
+    let batch_options = batching.then(|| ProducerOptions {
+        batch_size: Some(1000),
+        batch_timeout: Some(Duration::from_millis(10)),
+        ..Default::default()
+    });
+    Ok(PulsarBroker::new(
+        client,
+        "bench".to_string(),
+        "bench".to_string(),
+        SubType::KeyShared,
+        None,
+        "bench-dlq".to_string(),
+        pool_size,
+        batch_options,
+    ))
+}
+
+/// What `/send` does for a key with a known owner, from concurrent requests.
+async fn publish_all(broker: Arc<PulsarBroker>) {
+    let per_request = PUBLISHES / CONCURRENT_REQUESTS;
+    let handles: Vec<_> = (0..CONCURRENT_REQUESTS)
+        .map(|request| {
+            let broker = broker.clone();
+            tokio::spawn(async move {
+                for i in 0..per_request {
+                    let message = MessageData {
+                        data: r#"{"actionType":"info","data":{"text":"hello"}}"#.to_string(),
+                        partition_key: format!("user-{}#business#device-{}", request, i),
+                        deliver_at: None,
+                        control: false,
+                    };
+                    broker
+                        .publish(Destination::Instance("bench"), message)
+                        .await
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.await.unwrap();
+    }
+}
+
+/// A pool of one without batching is the former single `Mutex<Producer>`.
+fn broker_send(c: &mut Criterion, runtime: &Runtime) {
+    let mut group = c.benchmark_group("publish");
+    group.sample_size(10);
+    for batching in [false, true] {
+        for pool_size in [1, 4, 16] {
+            let broker = Arc::new(runtime.block_on(broker(pool_size, batching)).unwrap());
+            // creates the producers outside of the measurement
+            runtime.block_on(publish_all(broker.clone()));
+            let name = if batching { "batched" } else { "unbatched" };
+            group.bench_with_input(BenchmarkId::new(name, pool_size), &broker, |b, broker| {
+                b.to_async(runtime).iter(|| publish_all(broker.clone()))
+            });
+        }
+    }
+    group.finish();
+}
+
+fn benches(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    if let Err(e) = runtime.block_on(broker(1, false)) {
+        eprintln!("Pulsar is not reachable at {}: {:?}", pulsar_url(), e);
+        return;
+    }
+    broker_send(c, &runtime);
+}
+
+criterion_group!(send, benches);
+
+fn main() {
+    send();
+    Criterion::default().configure_from_args().final_summary();
+}
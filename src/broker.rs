@@ -0,0 +1,597 @@
+use crate::presence::PresenceDirectory;
+use crate::websocket::{
+    Deliver, DeliveryStatus, KickSessions, MessageTarget, MessageToClient, Server, ACK_TIMEOUT,
+};
+use actix::Addr;
+use actix_web_actors::ws::{CloseCode, CloseReason};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+/// Envelope published on every broker topic.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct MessageData {
+    pub data: String,
+    pub partition_key: String,
+    /// Epoch millis before which the broker holds the message back.
+    #[serde(skip)]
+    pub deliver_at: Option<i64>,
+    /// `data` is a `ControlMessage` between instances instead of a message for clients
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub control: bool,
+}
+
+/// Requests and replies exchanged between instances over their instance topics.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ControlMessage {
+    /// Close the sessions of a key or target, answered with `KickDone` on `reply_to`
+    Kick {
+        kick_id: Uuid,
+        reply_to: String,
+        id: Option<String>,
+        target: Option<MessageTarget>,
+        code: u16,
+        reason: Option<String>,
+    },
+    KickDone {
+        kick_id: Uuid,
+        instance_id: String,
+        closed: usize,
+    },
+}
+
+impl ControlMessage {
+    pub fn kick(kick_id: Uuid, reply_to: &str, kick: &KickSessions) -> Self {
+        Self::Kick {
+            kick_id,
+            reply_to: reply_to.to_string(),
+            id: kick.id.clone(),
+            target: kick.target.clone(),
+            code: kick.reason.code.into(),
+            reason: kick.reason.description.clone(),
+        }
+    }
+
+    pub fn to_message_data(&self) -> MessageData {
+        let partition_key = match self {
+            Self::Kick { kick_id, .. } | Self::KickDone { kick_id, .. } => kick_id.to_string(),
+        };
+        MessageData {
+            data: serde_json::to_string(self).unwrap(),
+            partition_key,
+            deliver_at: None,
+            control: true,
+        }
+    }
+}
+
+/// Instance that handled a kick and the number of sessions it closed.
+type KickReply = (String, usize);
+
+/// Kicks waiting for the other instances, by kick id.
+#[derive(Clone, Default)]
+pub struct PendingKicks {
+    inner: Arc<Mutex<HashMap<Uuid, mpsc::UnboundedSender<KickReply>>>>,
+}
+
+impl PendingKicks {
+    pub fn register(&self, kick_id: Uuid) -> mpsc::UnboundedReceiver<KickReply> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.inner.lock().unwrap().insert(kick_id, sender);
+        receiver
+    }
+
+    pub fn remove(&self, kick_id: &Uuid) {
+        self.inner.lock().unwrap().remove(kick_id);
+    }
+
+    fn resolve(&self, kick_id: &Uuid, instance_id: String, closed: usize) {
+        if let Some(sender) = self.inner.lock().unwrap().get(kick_id) {
+            let _ = sender.send((instance_id, closed));
+        }
+    }
+}
+
+/// Where a message is published.
+#[derive(Debug, Clone, Copy)]
+pub enum Destination<'a> {
+    /// Consumed by whichever instance the broker assigns the partition key to
+    Shared,
+    /// Consumed only by the given instance
+    Instance(&'a str),
+    /// Client actions for the backend
+    Inbound,
+    /// Key ownership changes
+    Presence,
+}
+
+/// What a subscription consumes.
+#[derive(Debug, Clone)]
+pub enum Source {
+    Shared,
+    Instance(String),
+    /// Full presence history, read by the given instance
+    Presence(String),
+    Inbound,
+}
+
+/// A message received from a subscription, acked or nacked through the same subscription.
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    /// Identifies the delivery within its subscription
+    pub handle: u64,
+    /// Broker message id, stable across redeliveries
+    pub message_id: String,
+    pub partition_key: String,
+    pub payload: Vec<u8>,
+}
+
+impl Delivery {
+    pub fn message_data(&self) -> Result<MessageData, serde_json::Error> {
+        serde_json::from_slice(&self.payload)
+    }
+}
+
+#[async_trait]
+pub trait MessageBroker: Send + Sync {
+    async fn publish(
+        &self,
+        destination: Destination<'_>,
+        message: MessageData,
+    ) -> Result<(), anyhow::Error>;
+
+    async fn subscribe(&self, source: Source)
+        -> Result<Box<dyn BrokerSubscription>, anyhow::Error>;
+
+    /// Moves a message that can't be delivered out of the way of its subscription.
+    async fn dead_letter(&self, delivery: &Delivery, reason: &str) -> Result<(), anyhow::Error>;
+
+    /// Whether client actions have somewhere to go.
+    fn accepts_inbound(&self) -> bool;
+}
+
+#[async_trait]
+pub trait BrokerSubscription: Send {
+    /// `None` once the subscription is closed.
+    async fn next(&mut self) -> Option<Result<Delivery, anyhow::Error>>;
+
+    async fn ack(&mut self, delivery: &Delivery) -> Result<(), anyhow::Error>;
+
+    /// Asks the broker to redeliver the message.
+    async fn nack(&mut self, delivery: &Delivery) -> Result<(), anyhow::Error>;
+}
+
+/// Shared with the HTTP handlers publishing messages and the consumers routing them.
+pub struct AppState {
+    pub broker: Arc<dyn MessageBroker>,
+    pub directory: PresenceDirectory,
+    /// Of this instance
+    pub instance_id: String,
+    pub kicks: PendingKicks,
+}
+
+const INBOUND_QUEUE_SIZE: usize = 1024;
+
+/// Hands client-originated messages to the task publishing them on the inbound destination.
+#[derive(Clone, Default)]
+pub struct InboundPublisher {
+    sender: Option<mpsc::Sender<MessageData>>,
+}
+
+impl InboundPublisher {
+    pub fn start(broker: Arc<dyn MessageBroker>) -> Self {
+        if !broker.accepts_inbound() {
+            return Self::default();
+        }
+        let (sender, mut receiver) = mpsc::channel::<MessageData>(INBOUND_QUEUE_SIZE);
+        tokio::spawn(async move {
+            while let Some(message) = receiver.recv().await {
+                if let Err(e) = broker.publish(Destination::Inbound, message).await {
+                    error!("Failed to publish inbound message: {:?}", e);
+                }
+            }
+        });
+        Self {
+            sender: Some(sender),
+        }
+    }
+
+    pub fn try_publish(&self, message: MessageData) -> Result<(), String> {
+        match &self.sender {
+            Some(sender) => sender
+                .try_send(message)
+                .map_err(|_| "Inbound queue is full, retry later".to_string()),
+            None => Err("Inbound messages are not enabled".to_string()),
+        }
+    }
+}
+
+/// Controls how often an undeliverable message is retried before it is dead-lettered.
+#[derive(Debug, Clone)]
+pub struct RedeliveryPolicy {
+    pub max_redelivery: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RedeliveryPolicy {
+    /// Exponential backoff for the given attempt, capped at `max_backoff`.
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+
+    /// How long a broker waits before resending an unacked message on its own. It outlasts the
+    /// client ack timeout and the longest backoff, so a message awaiting its ack or nack isn't
+    /// delivered twice.
+    pub fn resend_delay(&self) -> Duration {
+        ACK_TIMEOUT + self.backoff(self.max_redelivery.max(1)) + Duration::from_secs(5)
+    }
+}
+
+enum Outcome {
+    Ack,
+    /// Delivery failed, retried with backoff until `max_redelivery`
+    Failed,
+    /// Backoff elapsed, ask the broker to redeliver
+    Nack,
+}
+
+struct ConsumerState {
+    /// messages pushed to a session, resolved once the client answers
+    in_flight: HashMap<u64, Delivery>,
+    redeliveries: HashMap<String, u32>,
+    outcome_tx: mpsc::UnboundedSender<(u64, Outcome)>,
+    policy: RedeliveryPolicy,
+    broker: Arc<dyn MessageBroker>,
+}
+
+impl ConsumerState {
+    async fn resolve(
+        &mut self,
+        subscription: &mut Box<dyn BrokerSubscription>,
+        handle: u64,
+        outcome: Outcome,
+    ) {
+        let Some(delivery) = self.in_flight.remove(&handle) else {
+            return;
+        };
+        let result = match outcome {
+            Outcome::Ack => {
+                self.redeliveries.remove(&delivery.message_id);
+                subscription.ack(&delivery).await
+            }
+            Outcome::Nack => subscription.nack(&delivery).await,
+            Outcome::Failed => {
+                let attempts = self
+                    .redeliveries
+                    .entry(delivery.message_id.clone())
+                    .or_default();
+                *attempts += 1;
+                if *attempts > self.policy.max_redelivery {
+                    self.dead_letter(subscription, delivery, "Max redelivery exceeded")
+                        .await;
+                    return;
+                }
+                let backoff = self.policy.backoff(*attempts);
+                self.in_flight.insert(handle, delivery);
+                let outcome_tx = self.outcome_tx.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(backoff).await;
+                    let _ = outcome_tx.send((handle, Outcome::Nack));
+                });
+                return;
+            }
+        };
+        if let Err(e) = result {
+            error!("Failed to acknowledge message: {:?}", e);
+        }
+    }
+
+    /// Moves the message to the dead letter destination and acks it, it is nacked if that fails.
+    async fn dead_letter(
+        &mut self,
+        subscription: &mut Box<dyn BrokerSubscription>,
+        delivery: Delivery,
+        reason: &str,
+    ) {
+        self.redeliveries.remove(&delivery.message_id);
+        let result = match self.broker.dead_letter(&delivery, reason).await {
+            Ok(_) => subscription.ack(&delivery).await,
+            Err(e) => {
+                error!("Failed to dead letter message: {:?}", e);
+                subscription.nack(&delivery).await
+            }
+        };
+        if let Err(e) = result {
+            error!("Failed to acknowledge message: {:?}", e);
+        }
+    }
+}
+
+fn parse_message(message_data: &MessageData) -> Result<MessageToClient, String> {
+    serde_json::from_str::<MessageToClient>(&message_data.data).map_err(|e| e.to_string())
+}
+
+/// Runs a control message from another instance, replying to kicks on the sender's topic.
+async fn handle_control(
+    message_data: &MessageData,
+    app_state: &AppState,
+    websocket_client: &Addr<Server>,
+) -> Result<(), anyhow::Error> {
+    match serde_json::from_str::<ControlMessage>(&message_data.data)? {
+        ControlMessage::Kick {
+            kick_id,
+            reply_to,
+            id,
+            target,
+            code,
+            reason,
+        } => {
+            let kick = KickSessions {
+                id,
+                target,
+                reason: CloseReason {
+                    code: CloseCode::from(code),
+                    description: reason,
+                },
+            };
+            let closed = websocket_client.send(kick).await?;
+            let reply = ControlMessage::KickDone {
+                kick_id,
+                instance_id: app_state.instance_id.clone(),
+                closed,
+            };
+            app_state
+                .broker
+                .publish(Destination::Instance(&reply_to), reply.to_message_data())
+                .await
+        }
+        ControlMessage::KickDone {
+            kick_id,
+            instance_id,
+            closed,
+        } => {
+            app_state.kicks.resolve(&kick_id, instance_id, closed);
+            Ok(())
+        }
+    }
+}
+
+/// Hands a message of the shared topic to the other instances holding its keys, resolved when
+/// it is consumed so deferred messages reach the owners of their delivery time.
+/// Returns the number of instances it was published to.
+async fn forward_to_owners(
+    app_state: &AppState,
+    message_data: MessageData,
+    message: &MessageToClient,
+) -> Result<usize, anyhow::Error> {
+    let owners = app_state.directory.owners_for(message);
+    let mut forwarded = 0;
+    for instance_id in owners.iter().filter(|id| **id != app_state.instance_id) {
+        let message_data = MessageData {
+            deliver_at: None,
+            ..message_data.clone()
+        };
+        app_state
+            .broker
+            .publish(Destination::Instance(instance_id), message_data)
+            .await?;
+        forwarded += 1;
+    }
+    Ok(forwarded)
+}
+
+/// Exponential delay between attempts to reach the broker.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    /// Delay before the next attempt, doubled for the one after.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = self.current.saturating_mul(2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(500), Duration::from_secs(30))
+    }
+}
+
+/// Consecutive receive errors after which the subscription is recreated.
+const MAX_RECEIVE_ERRORS: u32 = 10;
+
+/// Subscribes to the source and pushes its messages to the WebSocket server, acking them once a
+/// client acks, they are buffered, forwarded to their owners, or dead-lettered. The subscription
+/// is recreated with backoff whenever it fails or ends.
+pub fn start_consumer(
+    source: Source,
+    app_state: Arc<AppState>,
+    websocket_client: Addr<Server>,
+    policy: RedeliveryPolicy,
+) {
+    let broker = app_state.broker.clone();
+    tokio::spawn(async move {
+        let mut backoff = Backoff::default();
+        // kept across subscriptions, message ids are stable across redeliveries
+        let mut redeliveries = HashMap::new();
+        loop {
+            match broker.subscribe(source.clone()).await {
+                Ok(subscription) => {
+                    backoff.reset();
+                    redeliveries = consume(
+                        subscription,
+                        &source,
+                        &app_state,
+                        &websocket_client,
+                        policy.clone(),
+                        redeliveries,
+                    )
+                    .await;
+                    warn!("Subscription to {:?} ended, resubscribing", source);
+                }
+                Err(e) => {
+                    error!("Failed to subscribe to {:?}: {:?}", source, e);
+                }
+            }
+            tokio::time::sleep(backoff.next_delay()).await;
+        }
+    });
+}
+
+/// Runs until the subscription ends or keeps failing, returning the redelivery counts.
+async fn consume(
+    mut subscription: Box<dyn BrokerSubscription>,
+    source: &Source,
+    app_state: &AppState,
+    websocket_client: &Addr<Server>,
+    policy: RedeliveryPolicy,
+    redeliveries: HashMap<String, u32>,
+) -> HashMap<String, u32> {
+    // a fresh channel per subscription, so outcomes of a previous one can't hit reused handles
+    let (outcome_tx, mut outcome_rx) = mpsc::unbounded_channel::<(u64, Outcome)>();
+    let mut state = ConsumerState {
+        in_flight: HashMap::new(),
+        redeliveries,
+        outcome_tx,
+        policy,
+        broker: app_state.broker.clone(),
+    };
+    let mut receive_errors = 0;
+    loop {
+        tokio::select! {
+            Some((handle, outcome)) = outcome_rx.recv() => {
+                state.resolve(&mut subscription, handle, outcome).await;
+            }
+            result = subscription.next() => {
+                let delivery = match result {
+                    Some(Ok(delivery)) => delivery,
+                    None => break,
+                    Some(Err(e)) => {
+                        error!("Failed to receive message: {:?}", e);
+                        receive_errors += 1;
+                        if receive_errors >= MAX_RECEIVE_ERRORS {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                receive_errors = 0;
+                let message_data = match delivery.message_data() {
+                    Ok(message_data) => message_data,
+                    Err(e) => {
+                        let reason = e.to_string();
+                        warn!("Invalid message payload: {}", reason);
+                        state.dead_letter(&mut subscription, delivery, &reason).await;
+                        continue;
+                    }
+                };
+                if message_data.control {
+                    // not retried, a kick that isn't answered in time is reported by its sender
+                    let result = handle_control(&message_data, app_state, websocket_client).await;
+                    if let Err(e) = result {
+                        error!("Failed to handle control message: {:?}", e);
+                    }
+                    if let Err(e) = subscription.ack(&delivery).await {
+                        error!("Failed to acknowledge message: {:?}", e);
+                    }
+                    continue;
+                }
+                let websocket_data = match parse_message(&message_data) {
+                    Ok(message) => message,
+                    Err(reason) => {
+                        warn!("Invalid message payload: {}", reason);
+                        state.dead_letter(&mut subscription, delivery, &reason).await;
+                        continue;
+                    }
+                };
+                let handle = delivery.handle;
+                let partition_key = delivery.partition_key.clone();
+                state.in_flight.insert(handle, delivery);
+                let forwarded = match source {
+                    Source::Shared => {
+                        match forward_to_owners(app_state, message_data, &websocket_data).await {
+                            Ok(forwarded) => forwarded,
+                            Err(e) => {
+                                error!("Failed to forward message to its owners: {:?}", e);
+                                state.resolve(&mut subscription, handle, Outcome::Failed).await;
+                                continue;
+                            }
+                        }
+                    }
+                    _ => 0,
+                };
+                let deliver = Deliver {
+                    message: websocket_data,
+                    // keys held elsewhere are buffered by their owners if they leave
+                    buffer: forwarded == 0,
+                };
+                match websocket_client.send(deliver).await {
+                    Ok(DeliveryStatus::Sent(client_ack)) => {
+                        let outcome_tx = state.outcome_tx.clone();
+                        tokio::spawn(async move {
+                            let outcome = match client_ack.await {
+                                Ok(_) => Outcome::Ack,
+                                Err(_) => Outcome::Failed,
+                            };
+                            let _ = outcome_tx.send((handle, outcome));
+                        });
+                    }
+                    Ok(DeliveryStatus::Buffered(stored)) => {
+                        // acked once the buffer holds it, a failed write is retried
+                        let outcome_tx = state.outcome_tx.clone();
+                        tokio::spawn(async move {
+                            let results = futures::future::join_all(stored).await;
+                            let outcome = if results.iter().all(|r| matches!(r, Ok(Ok(_)))) {
+                                Outcome::Ack
+                            } else {
+                                Outcome::Failed
+                            };
+                            let _ = outcome_tx.send((handle, outcome));
+                        });
+                    }
+                    Ok(DeliveryStatus::Unsubscribed) => {
+                        state.resolve(&mut subscription, handle, Outcome::Ack).await;
+                    }
+                    _ if forwarded > 0 => {
+                        state.resolve(&mut subscription, handle, Outcome::Ack).await;
+                    }
+                    _ => {
+                        info!(
+                            "No active WebSocket session found for partition key: {}",
+                            partition_key
+                        );
+                        state.resolve(&mut subscription, handle, Outcome::Failed).await;
+                    }
+                }
+            }
+        }
+    }
+    state.redeliveries
+}
@@ -0,0 +1,471 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tracing::error;
+
+/// How often expired messages of keys that never reconnect are removed.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A message that could not be delivered because no session was connected for its key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BufferedMessage {
+    pub sequence: u64,
+    /// Serialized `MessageToClient`
+    pub payload: String,
+    /// Epoch seconds after which the message is discarded.
+    pub expires_at: u64,
+}
+
+impl BufferedMessage {
+    pub fn new(sequence: u64, payload: String, ttl: Duration) -> Self {
+        Self {
+            sequence,
+            payload,
+            expires_at: now_secs() + ttl.as_secs(),
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at <= now
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Per-key store for messages awaiting a session, bounded in size and age.
+pub trait MessageBuffer: Send {
+    fn push(&mut self, key: &str, message: BufferedMessage) -> Result<(), anyhow::Error>;
+
+    /// Removes all messages for the key, returning the unexpired ones newer than `after_sequence` in order.
+    fn drain(
+        &mut self,
+        key: &str,
+        after_sequence: Option<u64>,
+    ) -> Result<Vec<BufferedMessage>, anyhow::Error>;
+
+    /// Removes the expired messages of every key, returning how many there were.
+    fn purge_expired(&mut self) -> Result<usize, anyhow::Error>;
+}
+
+pub struct MemoryBuffer {
+    messages: HashMap<String, VecDeque<BufferedMessage>>,
+    /// Every held message by sequence, so the oldest can be evicted across keys
+    order: BTreeSet<(u64, String)>,
+    max_messages: usize,
+    /// Most messages held over all keys
+    max_total: usize,
+}
+
+impl MemoryBuffer {
+    pub fn new(max_messages: usize, max_total: usize) -> Self {
+        Self {
+            messages: HashMap::new(),
+            order: BTreeSet::new(),
+            max_messages,
+            max_total,
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        let Some((sequence, key)) = self.order.pop_first() else {
+            return;
+        };
+        if let Some(queue) = self.messages.get_mut(&key) {
+            queue.retain(|m| m.sequence != sequence);
+            if queue.is_empty() {
+                self.messages.remove(&key);
+            }
+        }
+    }
+}
+
+impl Default for MemoryBuffer {
+    fn default() -> Self {
+        Self::new(100, 100_000)
+    }
+}
+
+impl MessageBuffer for MemoryBuffer {
+    fn push(&mut self, key: &str, message: BufferedMessage) -> Result<(), anyhow::Error> {
+        let now = now_secs();
+        let queue = self.messages.entry(key.to_string()).or_default();
+        let mut dropped = vec![];
+        queue.retain(|m| {
+            if m.is_expired(now) {
+                dropped.push(m.sequence);
+            }
+            !m.is_expired(now)
+        });
+        while queue.len() >= self.max_messages {
+            dropped.extend(queue.pop_front().map(|m| m.sequence));
+        }
+        self.order.insert((message.sequence, key.to_string()));
+        queue.push_back(message);
+        for sequence in dropped {
+            self.order.remove(&(sequence, key.to_string()));
+        }
+        while self.order.len() > self.max_total {
+            self.evict_oldest();
+        }
+        Ok(())
+    }
+
+    fn drain(
+        &mut self,
+        key: &str,
+        after_sequence: Option<u64>,
+    ) -> Result<Vec<BufferedMessage>, anyhow::Error> {
+        let now = now_secs();
+        let queue = self.messages.remove(key).unwrap_or_default();
+        for message in &queue {
+            self.order.remove(&(message.sequence, key.to_string()));
+        }
+        Ok(queue
+            .into_iter()
+            .filter(|m| !m.is_expired(now) && after_sequence.is_none_or(|s| m.sequence > s))
+            .collect())
+    }
+
+    fn purge_expired(&mut self) -> Result<usize, anyhow::Error> {
+        let now = now_secs();
+        let mut expired = vec![];
+        self.messages.retain(|key, queue| {
+            queue.retain(|m| {
+                if m.is_expired(now) {
+                    expired.push((m.sequence, key.clone()));
+                }
+                !m.is_expired(now)
+            });
+            !queue.is_empty()
+        });
+        for entry in &expired {
+            self.order.remove(entry);
+        }
+        Ok(expired.len())
+    }
+}
+
+/// Buffer persisted in a local sled database so messages survive a restart.
+/// Entries are keyed by `{ws_key}\0{sequence}` so a prefix scan yields them in order.
+pub struct SledBuffer {
+    tree: sled::Tree,
+    max_messages: usize,
+}
+
+impl SledBuffer {
+    pub fn open(path: &str, max_messages: usize) -> Result<Self, anyhow::Error> {
+        let db = sled::open(path).context("Failed to open message buffer store")?;
+        let tree = db.open_tree("offline_messages")?;
+        Ok(Self { tree, max_messages })
+    }
+
+    fn prefix(key: &str) -> Vec<u8> {
+        let mut prefix = key.as_bytes().to_vec();
+        prefix.push(0);
+        prefix
+    }
+
+    fn entry_key(key: &str, sequence: u64) -> Vec<u8> {
+        let mut entry_key = Self::prefix(key);
+        entry_key.extend_from_slice(&sequence.to_be_bytes());
+        entry_key
+    }
+}
+
+impl MessageBuffer for SledBuffer {
+    fn push(&mut self, key: &str, message: BufferedMessage) -> Result<(), anyhow::Error> {
+        let now = now_secs();
+        let mut entries = Vec::new();
+        for entry in self.tree.scan_prefix(Self::prefix(key)) {
+            let (entry_key, value) = entry?;
+            let buffered: BufferedMessage = serde_json::from_slice(&value)?;
+            if buffered.is_expired(now) {
+                self.tree.remove(entry_key)?;
+            } else {
+                entries.push(entry_key);
+            }
+        }
+        let overflow = (entries.len() + 1).saturating_sub(self.max_messages);
+        for entry_key in entries.into_iter().take(overflow) {
+            self.tree.remove(entry_key)?;
+        }
+        self.tree.insert(
+            Self::entry_key(key, message.sequence),
+            serde_json::to_vec(&message)?,
+        )?;
+        Ok(())
+    }
+
+    fn drain(
+        &mut self,
+        key: &str,
+        after_sequence: Option<u64>,
+    ) -> Result<Vec<BufferedMessage>, anyhow::Error> {
+        let now = now_secs();
+        let mut messages = Vec::new();
+        for entry in self.tree.scan_prefix(Self::prefix(key)) {
+            let (entry_key, value) = entry?;
+            self.tree.remove(entry_key)?;
+            let buffered: BufferedMessage = serde_json::from_slice(&value)?;
+            if !buffered.is_expired(now) && after_sequence.is_none_or(|s| buffered.sequence > s) {
+                messages.push(buffered);
+            }
+        }
+        self.tree.flush()?;
+        Ok(messages)
+    }
+
+    fn purge_expired(&mut self) -> Result<usize, anyhow::Error> {
+        let now = now_secs();
+        let mut expired = 0;
+        for entry in self.tree.iter() {
+            let (entry_key, value) = entry?;
+            let buffered: BufferedMessage = serde_json::from_slice(&value)?;
+            if buffered.is_expired(now) {
+                self.tree.remove(entry_key)?;
+                expired += 1;
+            }
+        }
+        Ok(expired)
+    }
+}
+
+/// Resolves once a pushed message is stored, fails if the buffer thread stopped before.
+pub type Stored = oneshot::Receiver<Result<(), anyhow::Error>>;
+
+enum BufferCommand {
+    Push {
+        key: String,
+        message: BufferedMessage,
+        stored: oneshot::Sender<Result<(), anyhow::Error>>,
+    },
+    Drain {
+        key: String,
+        after_sequence: Option<u64>,
+        reply: Box<dyn FnOnce(Vec<BufferedMessage>) + Send>,
+    },
+}
+
+/// Runs a `MessageBuffer` on a thread of its own, so its I/O doesn't block the actor using it.
+/// Commands are handled in the order they are sent, expired messages are purged in between.
+pub struct BufferWorker {
+    sender: mpsc::Sender<BufferCommand>,
+}
+
+impl BufferWorker {
+    pub fn start(mut buffer: Box<dyn MessageBuffer>) -> Self {
+        let (sender, receiver) = mpsc::channel::<BufferCommand>();
+        std::thread::Builder::new()
+            .name("message-buffer".to_string())
+            .spawn(move || {
+                let mut last_sweep = Instant::now();
+                loop {
+                    let timeout = SWEEP_INTERVAL.saturating_sub(last_sweep.elapsed());
+                    match receiver.recv_timeout(timeout) {
+                        Ok(BufferCommand::Push {
+                            key,
+                            message,
+                            stored,
+                        }) => {
+                            let result = buffer.push(&key, message);
+                            if let Err(err) = &result {
+                                error!("Failed to buffer message: {:?}", err);
+                            }
+                            let _ = stored.send(result);
+                        }
+                        Ok(BufferCommand::Drain {
+                            key,
+                            after_sequence,
+                            reply,
+                        }) => match buffer.drain(&key, after_sequence) {
+                            Ok(messages) => reply(messages),
+                            Err(err) => {
+                                error!("Failed to read buffered messages: {:?}", err);
+                                reply(vec![]);
+                            }
+                        },
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                    if last_sweep.elapsed() >= SWEEP_INTERVAL {
+                        last_sweep = Instant::now();
+                        if let Err(err) = buffer.purge_expired() {
+                            error!("Failed to purge expired messages: {:?}", err);
+                        }
+                    }
+                }
+            })
+            .expect("Failed to start the message buffer thread");
+        Self { sender }
+    }
+
+    pub fn push(&self, key: &str, message: BufferedMessage) -> Stored {
+        let (stored, receiver) = oneshot::channel();
+        // a stopped thread drops the command, which fails the receiver
+        let _ = self.sender.send(BufferCommand::Push {
+            key: key.to_string(),
+            message,
+            stored,
+        });
+        receiver
+    }
+
+    /// Drains the key like `MessageBuffer::drain` and calls `reply` with the messages.
+    pub fn drain(
+        &self,
+        key: &str,
+        after_sequence: Option<u64>,
+        reply: impl FnOnce(Vec<BufferedMessage>) + Send + 'static,
+    ) -> Result<(), anyhow::Error> {
+        self.sender
+            .send(BufferCommand::Drain {
+                key: key.to_string(),
+                after_sequence,
+                reply: Box::new(reply),
+            })
+            .map_err(|_| anyhow::anyhow!("Message buffer thread has stopped"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn message(sequence: u64) -> BufferedMessage {
+        BufferedMessage::new(sequence, format!("message {}", sequence), TTL)
+    }
+
+    fn sequences(messages: &[BufferedMessage]) -> Vec<u64> {
+        messages.iter().map(|m| m.sequence).collect()
+    }
+
+    #[test]
+    fn drain_returns_messages_after_last_seen_in_order() {
+        let mut buffer = MemoryBuffer::default();
+        for sequence in [10, 20, 30] {
+            buffer.push("key", message(sequence)).unwrap();
+        }
+        buffer.push("other", message(15)).unwrap();
+
+        let drained = buffer.drain("key", Some(10)).unwrap();
+        assert_eq!(sequences(&drained), vec![20, 30]);
+        // drained messages are gone, also those before last_seen
+        assert!(buffer.drain("key", None).unwrap().is_empty());
+        assert_eq!(sequences(&buffer.drain("other", None).unwrap()), vec![15]);
+    }
+
+    #[test]
+    fn drain_without_last_seen_returns_everything() {
+        let mut buffer = MemoryBuffer::default();
+        buffer.push("key", message(1)).unwrap();
+        buffer.push("key", message(2)).unwrap();
+        assert_eq!(sequences(&buffer.drain("key", None).unwrap()), vec![1, 2]);
+    }
+
+    #[test]
+    fn per_key_cap_drops_the_oldest() {
+        let mut buffer = MemoryBuffer::new(2, 100);
+        for sequence in 1..=3 {
+            buffer.push("key", message(sequence)).unwrap();
+        }
+        assert_eq!(sequences(&buffer.drain("key", None).unwrap()), vec![2, 3]);
+        assert!(buffer.order.is_empty());
+    }
+
+    #[test]
+    fn total_cap_evicts_the_oldest_across_keys() {
+        let mut buffer = MemoryBuffer::new(10, 3);
+        buffer.push("a", message(1)).unwrap();
+        buffer.push("b", message(2)).unwrap();
+        buffer.push("a", message(3)).unwrap();
+        buffer.push("b", message(4)).unwrap();
+
+        assert_eq!(sequences(&buffer.drain("a", None).unwrap()), vec![3]);
+        assert_eq!(sequences(&buffer.drain("b", None).unwrap()), vec![2, 4]);
+    }
+
+    #[test]
+    fn expired_messages_are_skipped_and_purged() {
+        let mut buffer = MemoryBuffer::default();
+        buffer
+            .push(
+                "idle",
+                BufferedMessage::new(1, "old".to_string(), Duration::ZERO),
+            )
+            .unwrap();
+        buffer.push("key", message(2)).unwrap();
+        buffer
+            .push(
+                "key",
+                BufferedMessage::new(3, "old".to_string(), Duration::ZERO),
+            )
+            .unwrap();
+
+        assert_eq!(buffer.purge_expired().unwrap(), 2);
+        assert!(!buffer.messages.contains_key("idle"));
+        assert_eq!(buffer.order.len(), 1);
+        assert_eq!(sequences(&buffer.drain("key", None).unwrap()), vec![2]);
+    }
+
+    #[test]
+    fn worker_drains_off_the_caller_thread() {
+        let worker = BufferWorker::start(Box::new(MemoryBuffer::default()));
+        worker
+            .push("key", message(1))
+            .blocking_recv()
+            .unwrap()
+            .unwrap();
+        worker
+            .push("key", message(2))
+            .blocking_recv()
+            .unwrap()
+            .unwrap();
+        let (sender, receiver) = mpsc::channel();
+        worker
+            .drain("key", Some(1), move |messages| {
+                let _ = sender.send(messages);
+            })
+            .unwrap();
+        let drained = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(sequences(&drained), vec![2]);
+    }
+
+    /// Fails every write, like a full disk under sled.
+    struct FailingBuffer;
+
+    impl MessageBuffer for FailingBuffer {
+        fn push(&mut self, _: &str, _: BufferedMessage) -> Result<(), anyhow::Error> {
+            anyhow::bail!("disk full")
+        }
+
+        fn drain(
+            &mut self,
+            _: &str,
+            _: Option<u64>,
+        ) -> Result<Vec<BufferedMessage>, anyhow::Error> {
+            Ok(vec![])
+        }
+
+        fn purge_expired(&mut self) -> Result<usize, anyhow::Error> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn worker_reports_failed_pushes() {
+        let worker = BufferWorker::start(Box::new(FailingBuffer));
+        let stored = worker.push("key", message(1)).blocking_recv().unwrap();
+        assert!(stored.is_err());
+    }
+}
@@ -1,16 +1,18 @@
 
 use secrecy::ExposeSecret;
 
+use uuid::Uuid;
+
 use crate::utils::{ generate_jwt_token_for_user, get_configuration};
 
 
 
 
 #[tracing::instrument(name = "Generate user token")]
-pub async fn generate_user_token(username: &str) {
+pub async fn generate_user_token(username: &str, business_id: Option<Uuid>, scopes: Vec<String>) {
     let configuration = get_configuration().expect("Failed to read configuration.");
 
-    let token = generate_jwt_token_for_user(username, configuration.secret.jwt.expiry, &configuration.secret.jwt.secret).map_err(|e| anyhow::anyhow!("JWT generation error: {}", e));
+    let token = generate_jwt_token_for_user(username, business_id, scopes, configuration.secret.jwt.expiry, &configuration.secret.jwt.secret).map_err(|e| anyhow::anyhow!("JWT generation error: {}", e));
 
     eprint!("Token for {} is: {}", username, token.unwrap().expose_secret())
 }
@@ -21,7 +23,15 @@ pub async fn generate_user_token(username: &str) {
 pub async fn run_custom_commands(args: Vec<String>) -> Result<(), anyhow::Error> {
     if args.len() > 1 {
         if args[1] == "generate_token" && args.len() > 2 {
-            generate_user_token(&args[2]).await;
+            let mut business_id = None;
+            let mut scopes = vec![];
+            for arg in &args[3..] {
+                match arg.strip_prefix("--scope=") {
+                    Some(scope) => scopes.extend(scope.split(',').map(str::to_string)),
+                    None => business_id = Some(Uuid::parse_str(arg)?),
+                }
+            }
+            generate_user_token(&args[2], business_id, scopes).await;
         }
     } else {
         eprintln!("Invalid command. Please enter a valid command.");
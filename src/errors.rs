@@ -25,6 +25,8 @@ pub enum GenericError {
     UnexpectedError(#[from] anyhow::Error),
     #[error("{0}")]
     InvalidJWT(String),
+    #[error("{0}")]
+    InsufficientPrivilege(String),
 }
 
 impl std::fmt::Debug for GenericError {
@@ -40,6 +42,7 @@ impl ResponseError for GenericError {
             GenericError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
 
             GenericError::InvalidJWT(_) => StatusCode::UNAUTHORIZED,
+            GenericError::InsufficientPrivilege(_) => StatusCode::FORBIDDEN,
         }
     }
 
@@ -50,6 +53,7 @@ impl ResponseError for GenericError {
             GenericError::ValidationError(message) => message.to_string(),
             GenericError::UnexpectedError(error_msg) => error_msg.to_string(),
             GenericError::InvalidJWT(error_msg) => error_msg.to_string(),
+            GenericError::InsufficientPrivilege(error_msg) => error_msg.to_string(),
         };
 
         HttpResponse::build(status_code)
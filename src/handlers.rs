@@ -1,10 +1,27 @@
+use crate::broker::{AppState, ControlMessage, Destination, InboundPublisher, MessageData};
 use crate::errors::GenericError;
-use crate::pulsar_client::{AppState, MessageData};
-use crate::schemas::{GenericResponse, ProcessType, WSKeyTrait, WSRequest, WebSocketParam};
-use crate::websocket::{MessageToClient, Server, WebSocketSession};
-use actix::Addr;
+use crate::long_poll::{Mailboxes, OpenMailbox, Poll, PollBatch, PollMailbox};
+use crate::schemas::{
+    ApplicationSetting, BatchItemResult, BatchSendResponse, DataResponse, GenericResponse,
+    InstanceResult, JWTClaims, KickRequest, KickResult, PollParam, PollResult, ProcessType,
+    SecretSetting, SessionFilter, ValidationSetting, WSBatchRequest, WSKeyTrait, WSRequest,
+    WebSocketParam, ADMIN_SCOPE, BROADCAST_SCOPE, SEND_SCOPE,
+};
+use crate::sse::SseSession;
+use crate::utils::{decode_token, get_websocket_token, websocket_protocols};
+use crate::websocket::{
+    BusinessSessionCount, ClientInfo, CountSessionsByBusiness, GetSession, ListSessions, Server,
+    SessionInfo, WebSocketSession,
+};
+use actix::{Actor, Addr};
+use actix_web::http::header::CACHE_CONTROL;
 use actix_web::{web, Error, HttpRequest, HttpResponse, Responder};
 use actix_web_actors::ws;
+use futures::future::join_all;
+use futures::StreamExt;
+use std::collections::HashSet;
+use std::time::Duration;
+use uuid::Uuid;
 #[utoipa::path(get, path = "/", tag = "Health Check")]
 pub async fn health_check() -> impl Responder {
     HttpResponse::Ok().body("Running Server")
@@ -20,24 +37,160 @@ pub async fn health_check() -> impl Responder {
         ("device_id" = Option<String>, Query, description = "Device Id"),
         ("user_id" = Option<String>, Query, description = "User Id"),
         ("business_id" = String, Query, description = "Business Id"),
+        ("token" = Option<String>, Query, description = "JWT token, can also be sent as `Sec-WebSocket-Protocol: access_token, <token>` or the `token` cookie"),
+        ("last_seen" = Option<u64>, Query, description = "Last received sequence, buffered messages after it are replayed"),
     )
 )]
-#[tracing::instrument(name = "Commence web socket", skip(stream), fields())]
+#[tracing::instrument(name = "Commence web socket", skip_all, fields(ws_key = %query.get_ws_key()))]
 pub async fn web_socket(
     req: HttpRequest,
     stream: web::Payload,
     query: web::Query<WebSocketParam>,
     server_addr: web::Data<Addr<Server>>,
+    secret: web::Data<SecretSetting>,
+    inbound_publisher: web::Data<InboundPublisher>,
+    application: web::Data<ApplicationSetting>,
 ) -> Result<HttpResponse, Error> {
-    let web_socket_key = query.get_ws_key();
-    let res = ws::start(
-        WebSocketSession::new(web_socket_key, server_addr.get_ref().clone()),
+    authorize_session(&req, &query, &secret)?;
+
+    let protocols = websocket_protocols();
+    let res = ws::WsResponseBuilder::new(
+        WebSocketSession::new(
+            &query,
+            ClientInfo::from_request(&req),
+            application.max_frame_bytes(),
+            server_addr.get_ref().clone(),
+            inbound_publisher.get_ref().clone(),
+        ),
         &req,
         stream,
-    )?;
+    )
+    .protocols(&protocols)
+    .start()?;
     Ok(res)
 }
 
+/// Checks the token of a connecting session against the key it connects as.
+fn authorize_session(
+    req: &HttpRequest,
+    query: &WebSocketParam,
+    secret: &SecretSetting,
+) -> Result<(), GenericError> {
+    let token = get_websocket_token(req, query.token.as_deref()).ok_or_else(|| {
+        GenericError::ValidationError("Authorization token is missing".to_string())
+    })?;
+    let claims = decode_token(token, &secret.jwt.secret)
+        .map_err(|e| GenericError::InvalidJWT(e.to_string()))?;
+    query.validate_claims(&claims)
+}
+
+#[utoipa::path(
+    get,
+    path = "/events",
+    tag = "WebSocket",
+    description = "Server-Sent Events fallback for clients behind proxies that strip WebSocket upgrades, it receives the same messages as `/websocket` under the same key.",
+    summary = "Event Stream API",
+    params(
+        ("device_id" = Option<String>, Query, description = "Device Id"),
+        ("user_id" = Option<String>, Query, description = "User Id"),
+        ("business_id" = String, Query, description = "Business Id"),
+        ("token" = Option<String>, Query, description = "JWT token, can also be sent as the `token` cookie"),
+        ("last_seen" = Option<u64>, Query, description = "Last received sequence, buffered messages after it are replayed"),
+        ("Last-Event-ID" = Option<u64>, Header, description = "Set by the browser on reconnect, takes precedence over `last_seen`"),
+    ),
+    responses(
+        (status=200, description= "`text/event-stream` of messages, the `id` of each event is its sequence", content_type = "text/event-stream", body=String),
+    ),
+)]
+#[tracing::instrument(name = "Commence event stream", skip_all, fields(ws_key = %query.get_ws_key()))]
+pub async fn events(
+    req: HttpRequest,
+    query: web::Query<WebSocketParam>,
+    server_addr: web::Data<Addr<Server>>,
+    secret: web::Data<SecretSetting>,
+) -> Result<HttpResponse, GenericError> {
+    authorize_session(&req, &query, &secret)?;
+
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    let (session, events) = SseSession::new(
+        &query,
+        last_event_id,
+        ClientInfo::from_request(&req),
+        server_addr.get_ref().clone(),
+    );
+    session.start();
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        // keeps nginx style proxies from buffering the stream
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(events.map(Ok::<_, Error>)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/poll",
+    tag = "WebSocket",
+    description = "Long-polling fallback for clients without WebSocket or SSE support. The request is held until messages for the key arrive or the timeout passes, messages are returned again until a later poll sends their `cursor`.",
+    summary = "Long Poll API",
+    params(
+        ("device_id" = Option<String>, Query, description = "Device Id"),
+        ("user_id" = Option<String>, Query, description = "User Id"),
+        ("business_id" = String, Query, description = "Business Id"),
+        ("token" = Option<String>, Query, description = "JWT token, can also be sent as the `token` cookie"),
+        ("session" = Option<String>, Query, description = "`session` of the previous response, a new one is started when it is missing or expired"),
+        ("cursor" = Option<u64>, Query, description = "`cursor` of the previous response"),
+        ("timeout_secs" = Option<u64>, Query, description = "How long to wait for messages, 25 by default and at most 30"),
+    ),
+    responses(
+        (status=200, description= "Messages after the cursor, empty on timeout", body=DataResponse<PollResult>),
+    ),
+)]
+#[tracing::instrument(name = "long_poll", skip_all, fields(ws_key = %query.get_ws_key()))]
+pub async fn long_poll(
+    req: HttpRequest,
+    query: web::Query<WebSocketParam>,
+    poll: web::Query<PollParam>,
+    mailboxes: web::Data<Addr<Mailboxes>>,
+    server_addr: web::Data<Addr<Server>>,
+    secret: web::Data<SecretSetting>,
+) -> Result<web::Json<DataResponse<PollResult>>, GenericError> {
+    authorize_session(&req, &query, &secret)?;
+
+    let mailbox = mailboxes
+        .send(OpenMailbox {
+            session: poll.session,
+            mailbox: PollMailbox::new(
+                &query,
+                poll.cursor,
+                ClientInfo::from_request(&req),
+                server_addr.get_ref().clone(),
+            ),
+        })
+        .await
+        .map_err(|e| GenericError::UnexpectedError(e.into()))?;
+    let pending = mailbox
+        .addr
+        .send(Poll {
+            cursor: poll.cursor,
+        })
+        .await
+        .map_err(|e| GenericError::UnexpectedError(e.into()))?;
+    // a timeout, or a newer poll taking over, ends the request empty
+    let batch = match tokio::time::timeout(poll.timeout(), pending.0).await {
+        Ok(Ok(batch)) => batch,
+        _ => PollBatch::default(),
+    };
+    Ok(web::Json(DataResponse::success(
+        "Successfully polled messages",
+        PollResult::new(batch, poll.cursor, mailbox.session),
+    )))
+}
+
 #[utoipa::path(
     post,
     path = "/send",
@@ -51,34 +204,325 @@ pub async fn web_socket(
     request_body(content = WSRequest, description = "Request Body"),
     responses(
         (status=200, description= "Web Socket response", body=GenericResponse),
+        (status=400, description= "Invalid request, or a payload not matching the action type in strict validation mode", body=GenericResponse),
+        (status=403, description= "Token without the send scope, or a broadcast without the broadcast scope", body=GenericResponse),
     ),
 
 
 )]
-#[tracing::instrument(name = "send_web_socket", skip(pulsar_client))]
+#[tracing::instrument(name = "send_web_socket", skip(app_state, claims, validation))]
 pub async fn send_web_socket(
     req: WSRequest,
     websocket_srv: web::Data<Addr<Server>>,
-    pulsar_client: web::Data<AppState>,
+    app_state: web::Data<AppState>,
+    claims: web::ReqData<JWTClaims>,
+    validation: web::Data<ValidationSetting>,
 ) -> Result<web::Json<GenericResponse>, GenericError> {
-    let ws_json = serde_json::to_value(&req.data).unwrap();
-    let ws_key = &req.get_ws_key();
-    let msg = MessageToClient::new(req.action_type, ws_json, Some(ws_key.to_string()));
-    let mut producer = pulsar_client.producer.lock().await;
-    // let a = ProducerMessage {};
-    if req.process_type.is_none() {
-        producer
-            .send_non_blocking(MessageData {
-                partition_key: ws_key.to_string(),
-                data: serde_json::to_string(&msg).unwrap(),
-            })
-            .await
-            .map_err(|e| GenericError::UnexpectedError(e.into()))?;
-    } else if req.process_type == Some(ProcessType::Immediate) {
+    require_send(&claims)?;
+    send_message(&req, &claims, &websocket_srv, &app_state, &validation).await?;
+    Ok(web::Json(GenericResponse::success(
+        "Successfully send Web Socket Notification",
+    )))
+}
+
+/// Validates a `/send` request and delivers it directly or through the broker.
+pub(crate) async fn send_message(
+    req: &WSRequest,
+    claims: &JWTClaims,
+    websocket_srv: &Addr<Server>,
+    app_state: &AppState,
+    validation: &ValidationSetting,
+) -> Result<(), GenericError> {
+    let deliver_at = req.deliver_at_millis()?;
+    validation.validate(req.action_type, &req.data)?;
+    let msg = req.to_message()?;
+    if msg.is_broadcast() && !claims.has_scope(BROADCAST_SCOPE) {
+        return Err(GenericError::InsufficientPrivilege(
+            "Token is not allowed to broadcast to all sessions".to_string(),
+        ));
+    }
+    if req.process_type == Some(ProcessType::Immediate) {
         websocket_srv.do_send(msg);
+    } else {
+        let message_data = MessageData {
+            partition_key: msg.partition_key(),
+            data: serde_json::to_string(&msg).unwrap(),
+            deliver_at,
+            control: false,
+        };
+        // route to the instances holding the sessions, the shared topic when no owner is known.
+        // Deferred messages always take the shared topic: instance topics are read exclusively,
+        // which ignores the delivery time, and the owner may change until then
+        let owners = match deliver_at {
+            Some(_) => HashSet::new(),
+            None => app_state.directory.owners_for(&msg),
+        };
+        if owners.is_empty() {
+            app_state
+                .broker
+                .publish(Destination::Shared, message_data)
+                .await
+                .map_err(GenericError::UnexpectedError)?;
+        } else {
+            for instance_id in owners {
+                app_state
+                    .broker
+                    .publish(Destination::Instance(&instance_id), message_data.clone())
+                    .await
+                    .map_err(GenericError::UnexpectedError)?;
+            }
+        }
     }
+    Ok(())
+}
 
-    Ok(web::Json(GenericResponse::success(
-        "Successfully send Web Socket Notification",
+#[utoipa::path(
+    post,
+    path = "/send/batch",
+    tag = "WebSocket",
+    description = "Send several WebSocket notifications, each item is validated and published on its own and gets its own result.",
+    summary = "Batch Send WebSocket API",
+    params(
+        ("Authorization" = String, Header, description = "JWT token"),
+    ),
+    request_body(content = Vec<WSRequest>, description = "Request Body"),
+    responses(
+        (status=200, description= "Per item results", body=BatchSendResponse),
+        (status=400, description= "Body is not an array or has too many items", body=GenericResponse),
+        (status=403, description= "Token without the send scope", body=GenericResponse),
+    ),
+)]
+#[tracing::instrument(name = "send_web_socket_batch", skip(req, app_state, claims, validation), fields(items = req.0.len()))]
+pub async fn send_web_socket_batch(
+    req: WSBatchRequest,
+    websocket_srv: web::Data<Addr<Server>>,
+    app_state: web::Data<AppState>,
+    claims: web::ReqData<JWTClaims>,
+    validation: web::Data<ValidationSetting>,
+) -> Result<web::Json<BatchSendResponse>, GenericError> {
+    require_send(&claims)?;
+    // published concurrently so the producers batch them together
+    let results = join_all(req.0.into_iter().enumerate().map(|(index, item)| {
+        let (claims, websocket_srv, app_state, validation) =
+            (&claims, &websocket_srv, &app_state, &validation);
+        async move {
+            let result = match serde_json::from_value::<WSRequest>(item) {
+                Ok(item) => send_message(&item, claims, websocket_srv, app_state, validation).await,
+                Err(e) => Err(GenericError::ValidationError(e.to_string())),
+            };
+            BatchItemResult::new(index, result)
+        }
+    }))
+    .await;
+    Ok(web::Json(BatchSendResponse::new(results)))
+}
+
+/// Client tokens share the secret and claims of service tokens, only the scope tells them apart.
+fn require_send(claims: &JWTClaims) -> Result<(), GenericError> {
+    if !claims.has_scope(SEND_SCOPE) {
+        return Err(GenericError::InsufficientPrivilege(
+            "Token is not allowed to send messages".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn require_admin(claims: &JWTClaims) -> Result<(), GenericError> {
+    if !claims.has_scope(ADMIN_SCOPE) {
+        return Err(GenericError::InsufficientPrivilege(
+            "Token is not allowed to use the admin API".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/sessions",
+    tag = "Admin",
+    description = "Sessions connected to the instance serving the request, requires the admin scope.",
+    summary = "List Sessions API",
+    params(
+        ("Authorization" = String, Header, description = "JWT token"),
+        ("business_id" = Option<String>, Query, description = "Only sessions of this business"),
+        ("user_id" = Option<String>, Query, description = "Only sessions of this user"),
+    ),
+    responses(
+        (status=200, description= "Active sessions of the instance", body=DataResponse<InstanceResult<Vec<SessionInfo>>>),
+        (status=403, description= "Token without the admin scope", body=GenericResponse),
+    ),
+)]
+#[tracing::instrument(name = "list_sessions", skip(websocket_srv, app_state, claims))]
+pub async fn list_sessions(
+    query: web::Query<SessionFilter>,
+    websocket_srv: web::Data<Addr<Server>>,
+    app_state: web::Data<AppState>,
+    claims: web::ReqData<JWTClaims>,
+) -> Result<web::Json<DataResponse<InstanceResult<Vec<SessionInfo>>>>, GenericError> {
+    require_admin(&claims)?;
+    let sessions = websocket_srv
+        .send(ListSessions {
+            business_id: query.business_id,
+            user_id: query.user_id,
+        })
+        .await
+        .map_err(|e| GenericError::UnexpectedError(e.into()))?;
+    Ok(web::Json(DataResponse::success(
+        "Successfully fetched sessions",
+        InstanceResult {
+            instance_id: app_state.instance_id.clone(),
+            result: sessions,
+        },
+    )))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/sessions/{conn_id}",
+    tag = "Admin",
+    description = "Details of a connection of the instance serving the request, requires the admin scope.",
+    summary = "Session Detail API",
+    params(
+        ("Authorization" = String, Header, description = "JWT token"),
+        ("conn_id" = String, Path, description = "Connection id"),
+    ),
+    responses(
+        (status=200, description= "Session details", body=DataResponse<InstanceResult<SessionInfo>>),
+        (status=400, description= "No such session on the instance", body=GenericResponse),
+        (status=403, description= "Token without the admin scope", body=GenericResponse),
+    ),
+)]
+#[tracing::instrument(name = "get_session", skip(websocket_srv, app_state, claims))]
+pub async fn get_session(
+    path: web::Path<Uuid>,
+    websocket_srv: web::Data<Addr<Server>>,
+    app_state: web::Data<AppState>,
+    claims: web::ReqData<JWTClaims>,
+) -> Result<web::Json<DataResponse<InstanceResult<SessionInfo>>>, GenericError> {
+    require_admin(&claims)?;
+    let session = websocket_srv
+        .send(GetSession {
+            conn_id: path.into_inner(),
+        })
+        .await
+        .map_err(|e| GenericError::UnexpectedError(e.into()))?
+        .ok_or_else(|| GenericError::ValidationError("Session not found".to_string()))?;
+    Ok(web::Json(DataResponse::success(
+        "Successfully fetched session",
+        InstanceResult {
+            instance_id: app_state.instance_id.clone(),
+            result: session,
+        },
+    )))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/sessions/count",
+    tag = "Admin",
+    description = "Connections per business on the instance serving the request, requires the admin scope.",
+    summary = "Session Count API",
+    params(
+        ("Authorization" = String, Header, description = "JWT token"),
+    ),
+    responses(
+        (status=200, description= "Sessions per business on the instance", body=DataResponse<InstanceResult<Vec<BusinessSessionCount>>>),
+        (status=403, description= "Token without the admin scope", body=GenericResponse),
+    ),
+)]
+#[tracing::instrument(name = "count_sessions", skip(websocket_srv, app_state, claims))]
+pub async fn count_sessions(
+    websocket_srv: web::Data<Addr<Server>>,
+    app_state: web::Data<AppState>,
+    claims: web::ReqData<JWTClaims>,
+) -> Result<web::Json<DataResponse<InstanceResult<Vec<BusinessSessionCount>>>>, GenericError> {
+    require_admin(&claims)?;
+    let counts = websocket_srv
+        .send(CountSessionsByBusiness)
+        .await
+        .map_err(|e| GenericError::UnexpectedError(e.into()))?;
+    Ok(web::Json(DataResponse::success(
+        "Successfully counted sessions",
+        InstanceResult {
+            instance_id: app_state.instance_id.clone(),
+            result: counts,
+        },
+    )))
+}
+
+/// How long a kick waits for the other instances holding the sessions.
+const KICK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[utoipa::path(
+    post,
+    path = "/admin/sessions/kick",
+    tag = "Admin",
+    description = "Closes the sessions of a key, user or business with a close frame on every instance holding them, requires the admin scope.",
+    summary = "Kick Sessions API",
+    params(
+        ("Authorization" = String, Header, description = "JWT token"),
+    ),
+    request_body(content = KickRequest, description = "Request Body"),
+    responses(
+        (status=200, description= "Number of closed connections, and the instances that didn't answer in time", body=DataResponse<KickResult>),
+        (status=400, description= "Invalid target, close code or reason", body=GenericResponse),
+        (status=403, description= "Token without the admin scope", body=GenericResponse),
+    ),
+)]
+#[tracing::instrument(name = "kick_sessions", skip(websocket_srv, app_state, claims))]
+pub async fn kick_sessions(
+    req: KickRequest,
+    websocket_srv: web::Data<Addr<Server>>,
+    app_state: web::Data<AppState>,
+    claims: web::ReqData<JWTClaims>,
+) -> Result<web::Json<DataResponse<KickResult>>, GenericError> {
+    require_admin(&claims)?;
+    let kick = req.to_kick()?;
+    let mut pending: HashSet<String> = app_state
+        .directory
+        .owners_of(kick.id.as_deref(), kick.target.as_ref())
+        .into_iter()
+        .filter(|id| *id != app_state.instance_id)
+        .collect();
+    let kick_id = Uuid::new_v4();
+    let mut replies = app_state.kicks.register(kick_id);
+    let control = ControlMessage::kick(kick_id, &app_state.instance_id, &kick);
+    let result = async {
+        for instance_id in &pending {
+            app_state
+                .broker
+                .publish(
+                    Destination::Instance(instance_id),
+                    control.to_message_data(),
+                )
+                .await
+                .map_err(GenericError::UnexpectedError)?;
+        }
+        let mut closed = websocket_srv
+            .send(kick)
+            .await
+            .map_err(|e| GenericError::UnexpectedError(e.into()))?;
+        // an instance that doesn't answer in time is reported, its sessions may still be open
+        let _ = tokio::time::timeout(KICK_TIMEOUT, async {
+            while !pending.is_empty() {
+                let Some((instance_id, count)) = replies.recv().await else {
+                    break;
+                };
+                if pending.remove(&instance_id) {
+                    closed += count;
+                }
+            }
+        })
+        .await;
+        Ok::<_, GenericError>(KickResult {
+            closed,
+            unconfirmed: pending.into_iter().collect(),
+        })
+    }
+    .await;
+    app_state.kicks.remove(&kick_id);
+    Ok(web::Json(DataResponse::success(
+        "Successfully closed sessions",
+        result?,
     )))
 }
@@ -0,0 +1,487 @@
+use crate::broker::{
+    BrokerSubscription, Delivery, Destination, MessageBroker, MessageData, Source,
+};
+use async_trait::async_trait;
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+use rdkafka::error::KafkaError;
+use rdkafka::message::{Header, Headers, Message, OwnedHeaders, OwnedMessage};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::{Offset, TopicPartitionList};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::error;
+
+const DELIVER_AT_HEADER: &str = "deliver_at";
+const TARGET_TOPIC_HEADER: &str = "target_topic";
+/// Longest a deferred record is held by the delay mover, one due later is produced to the delay
+/// topic again so its offset doesn't hold back the commits of the partition.
+const PARK_WINDOW: Duration = Duration::from_secs(300);
+/// Records read ahead of the delay mover.
+const DELAYED_QUEUE_SIZE: usize = 1024;
+/// Most records the delay mover holds, the delay topic is paused beyond it.
+const MAX_PARKED: usize = 10_000;
+
+/// `MessageBroker` on Kafka, `partition_key` is the record key so each key stays on one partition
+/// and in order. Offsets are committed only once every earlier record of the partition is acked.
+/// Deferred records wait on the `{topic}-delayed` topic and are moved to their topic once due.
+pub struct KafkaBroker {
+    brokers: String,
+    producer: FutureProducer,
+    topic: String,
+    group: String,
+    /// Unique per instance
+    consumer_name: String,
+    inbound_topic: Option<String>,
+    dead_letter_topic: String,
+}
+
+impl KafkaBroker {
+    #[tracing::instrument]
+    pub fn new(
+        brokers: String,
+        topic: String,
+        group: String,
+        consumer_name: String,
+        inbound_topic: Option<String>,
+        dead_letter_topic: String,
+    ) -> Result<Self, KafkaError> {
+        tracing::info!("Creating the Kafka producer.");
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", &brokers)
+            .set("client.id", &consumer_name)
+            .create()?;
+        Ok(Self {
+            brokers,
+            producer,
+            topic,
+            group,
+            consumer_name,
+            inbound_topic,
+            dead_letter_topic,
+        })
+    }
+
+    fn destination_topic(&self, destination: Destination<'_>) -> Result<String, anyhow::Error> {
+        Ok(match destination {
+            Destination::Shared => self.topic.clone(),
+            Destination::Instance(instance_id) => format!("{}-{}", self.topic, instance_id),
+            Destination::Presence => format!("{}-presence", self.topic),
+            Destination::Inbound => match &self.inbound_topic {
+                Some(topic) => topic.clone(),
+                None => anyhow::bail!("Inbound topic is not configured"),
+            },
+        })
+    }
+
+    fn delayed_topic(&self) -> String {
+        format!("{}-delayed", self.topic)
+    }
+
+    /// Moves the records of the delay topic to their topic once due, the topic is shared by all
+    /// instances through the `{group}-delayed` consumer group.
+    pub fn start_delay_mover(&self) -> Result<(), KafkaError> {
+        let topic = self.delayed_topic();
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", &self.brokers)
+            .set("group.id", format!("{}-delayed", self.group))
+            .set("client.id", &self.consumer_name)
+            .set("enable.auto.commit", "false")
+            .set("auto.offset.reset", "earliest")
+            .create()?;
+        consumer.subscribe(&[topic.as_str()])?;
+        tokio::spawn(move_delayed(Arc::new(consumer), self.producer.clone()));
+        Ok(())
+    }
+
+    async fn send(
+        &self,
+        topic: &str,
+        key: &str,
+        payload: &[u8],
+        headers: OwnedHeaders,
+    ) -> Result<(), KafkaError> {
+        send(&self.producer, topic, key, payload, headers).await
+    }
+}
+
+async fn send(
+    producer: &FutureProducer,
+    topic: &str,
+    key: &str,
+    payload: &[u8],
+    headers: OwnedHeaders,
+) -> Result<(), KafkaError> {
+    let record = FutureRecord::to(topic)
+        .key(key)
+        .payload(payload)
+        .headers(headers);
+    producer
+        .send(record, Duration::from_secs(0))
+        .await
+        .map_err(|(e, _)| e)?;
+    Ok(())
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+#[async_trait]
+impl MessageBroker for KafkaBroker {
+    async fn publish(
+        &self,
+        destination: Destination<'_>,
+        message: MessageData,
+    ) -> Result<(), anyhow::Error> {
+        let topic = self.destination_topic(destination)?;
+        let payload = serde_json::to_vec(&message)?;
+        // Kafka has no delayed delivery, the record waits on the delay topic until then
+        if let Some(deliver_at) = message.deliver_at.filter(|at| *at > now_millis()) {
+            let headers = OwnedHeaders::new()
+                .insert(Header {
+                    key: DELIVER_AT_HEADER,
+                    value: Some(&deliver_at.to_string()),
+                })
+                .insert(Header {
+                    key: TARGET_TOPIC_HEADER,
+                    value: Some(&topic),
+                });
+            self.send(
+                &self.delayed_topic(),
+                &message.partition_key,
+                &payload,
+                headers,
+            )
+            .await?;
+            return Ok(());
+        }
+        self.send(
+            &topic,
+            &message.partition_key,
+            &payload,
+            OwnedHeaders::new(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn subscribe(
+        &self,
+        source: Source,
+    ) -> Result<Box<dyn BrokerSubscription>, anyhow::Error> {
+        // (topic, group, commits, offset reset)
+        let (topic, group, commits, offset_reset) = match &source {
+            Source::Shared => (
+                self.destination_topic(Destination::Shared)?,
+                self.group.clone(),
+                true,
+                "latest",
+            ),
+            // from the start, records forwarded before the group's first commit are this instance's
+            Source::Instance(instance_id) => (
+                self.destination_topic(Destination::Instance(instance_id))?,
+                format!("{}-{}", self.group, instance_id),
+                true,
+                "earliest",
+            ),
+            // never committed, so every start replays the presence topic, which must be compacted:
+            // retention would delete the online records of long-lived connections
+            Source::Presence(instance_id) => (
+                self.destination_topic(Destination::Presence)?,
+                format!("{}-presence-{}", self.group, instance_id),
+                false,
+                "earliest",
+            ),
+            Source::Inbound => (
+                self.destination_topic(Destination::Inbound)?,
+                format!("{}-inbound", self.group),
+                true,
+                "latest",
+            ),
+        };
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", &self.brokers)
+            .set("group.id", &group)
+            .set("client.id", &self.consumer_name)
+            .set("enable.auto.commit", "false")
+            .set("auto.offset.reset", offset_reset)
+            .create()?;
+        consumer.subscribe(&[topic.as_str()])?;
+        let consumer = Arc::new(consumer);
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(read_records(consumer.clone(), sender.clone()));
+        Ok(Box::new(KafkaSubscription {
+            consumer,
+            commits,
+            sender,
+            receiver,
+            pending: HashMap::new(),
+            offsets: HashMap::new(),
+            next_handle: 0,
+        }))
+    }
+
+    async fn dead_letter(&self, delivery: &Delivery, reason: &str) -> Result<(), anyhow::Error> {
+        let headers = OwnedHeaders::new()
+            .insert(Header {
+                key: "reason",
+                value: Some(reason),
+            })
+            .insert(Header {
+                key: "origin_message_id",
+                value: Some(&delivery.message_id),
+            });
+        self.send(
+            &self.dead_letter_topic,
+            &delivery.partition_key,
+            &delivery.payload,
+            headers,
+        )
+        .await?;
+        Ok(())
+    }
+
+    fn accepts_inbound(&self) -> bool {
+        self.inbound_topic.is_some()
+    }
+}
+
+/// Reads records on a task of its own so the subscription's `next` stays cancel-safe.
+async fn read_records(
+    consumer: Arc<StreamConsumer>,
+    sender: mpsc::UnboundedSender<Result<OwnedMessage, KafkaError>>,
+) {
+    while !sender.is_closed() {
+        match consumer.recv().await {
+            Ok(record) => {
+                let _ = sender.send(Ok(record.detach()));
+            }
+            Err(e) => {
+                let _ = sender.send(Err(e));
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+fn header<'a>(record: &'a OwnedMessage, key: &str) -> Option<&'a str> {
+    record
+        .headers()?
+        .iter()
+        .find(|header| header.key == key)
+        .and_then(|header| std::str::from_utf8(header.value?).ok())
+}
+
+/// Holds the records of the delay topic for at most `PARK_WINDOW`, producing each to its target
+/// topic once due, or back to the delay topic when it isn't by then. Offsets are committed once
+/// every earlier record of the partition was produced again.
+async fn move_delayed(consumer: Arc<StreamConsumer>, producer: FutureProducer) {
+    let (sender, mut receiver) = mpsc::channel(DELAYED_QUEUE_SIZE);
+    let reader = consumer.clone();
+    tokio::spawn(async move {
+        while !sender.is_closed() {
+            match reader.recv().await {
+                Ok(record) => {
+                    let _ = sender.send(record.detach()).await;
+                }
+                Err(e) => {
+                    error!("Failed to read delayed record: {:?}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    });
+    // by wake-up time in epoch millis, then offset
+    let mut parked: BTreeMap<(i64, String, i32, i64), OwnedMessage> = BTreeMap::new();
+    let mut offsets: HashMap<(String, i32), PartitionOffsets> = HashMap::new();
+    let mut paused = false;
+    loop {
+        // stops fetching while full, the parked records are moved on within `PARK_WINDOW`
+        let full = parked.len() >= MAX_PARKED;
+        if full != paused {
+            match set_paused(&consumer, full) {
+                Ok(_) => paused = full,
+                Err(e) => error!("Failed to pause the delay topic: {:?}", e),
+            }
+        }
+        let next_wake = parked.keys().next().map(|(wake_at, ..)| *wake_at);
+        let sleep = Duration::from_millis(
+            next_wake
+                .map_or(PARK_WINDOW.as_millis() as i64, |at| at - now_millis())
+                .max(0) as u64,
+        );
+        tokio::select! {
+            record = receiver.recv(), if !full => {
+                let Some(record) = record else {
+                    break;
+                };
+                let deliver_at: i64 = header(&record, DELIVER_AT_HEADER)
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or_default();
+                let wake_at = deliver_at.min(now_millis() + PARK_WINDOW.as_millis() as i64);
+                let partition = (record.topic().to_string(), record.partition());
+                let partition_offsets = offsets.entry(partition.clone()).or_default();
+                partition_offsets.in_flight.insert(record.offset());
+                partition_offsets.last = partition_offsets.last.max(record.offset());
+                parked.insert((wake_at, partition.0, partition.1, record.offset()), record);
+            }
+            _ = tokio::time::sleep(sleep), if next_wake.is_some() => {
+                let Some((key, record)) = parked.pop_first() else {
+                    continue;
+                };
+                if let Err(e) = move_record(&producer, &record).await {
+                    error!("Failed to move delayed record: {:?}", e);
+                    parked.insert((now_millis() + 1000, key.1, key.2, key.3), record);
+                    continue;
+                }
+                let partition = (key.1, key.2);
+                let Some(partition_offsets) = offsets.get_mut(&partition) else {
+                    continue;
+                };
+                partition_offsets.in_flight.remove(&key.3);
+                let mut list = TopicPartitionList::new();
+                let result = list
+                    .add_partition_offset(
+                        &partition.0,
+                        partition.1,
+                        Offset::Offset(partition_offsets.committable()),
+                    )
+                    .and_then(|_| consumer.commit(&list, CommitMode::Async));
+                if let Err(e) = result {
+                    error!("Failed to commit delayed records: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Stops or resumes fetching the partitions of the delay topic assigned to this instance.
+fn set_paused(consumer: &StreamConsumer, paused: bool) -> Result<(), KafkaError> {
+    let assignment = consumer.assignment()?;
+    if paused {
+        consumer.pause(&assignment)
+    } else {
+        consumer.resume(&assignment)
+    }
+}
+
+/// Produces a due record to its target topic, one that isn't due yet to the delay topic again.
+async fn move_record(producer: &FutureProducer, record: &OwnedMessage) -> Result<(), KafkaError> {
+    let key = record
+        .key()
+        .map(|key| String::from_utf8_lossy(key).into_owned())
+        .unwrap_or_default();
+    let payload = record.payload().unwrap_or_default();
+    let due = header(record, DELIVER_AT_HEADER)
+        .and_then(|value| value.parse::<i64>().ok())
+        .is_none_or(|deliver_at| deliver_at <= now_millis());
+    match header(record, TARGET_TOPIC_HEADER) {
+        Some(topic) if due => send(producer, topic, &key, payload, OwnedHeaders::new()).await,
+        _ => {
+            let headers = record.headers().cloned().unwrap_or_default();
+            send(producer, record.topic(), &key, payload, headers).await
+        }
+    }
+}
+
+/// Offsets of one partition handed out but not yet acked.
+#[derive(Default)]
+struct PartitionOffsets {
+    in_flight: BTreeSet<i64>,
+    /// Highest offset handed out
+    last: i64,
+}
+
+impl PartitionOffsets {
+    /// Offset to commit: all records before it are done.
+    fn committable(&self) -> i64 {
+        self.in_flight.first().copied().unwrap_or(self.last + 1)
+    }
+}
+
+pub struct KafkaSubscription {
+    consumer: Arc<StreamConsumer>,
+    /// false for the presence topic, which is replayed on every start
+    commits: bool,
+    sender: mpsc::UnboundedSender<Result<OwnedMessage, KafkaError>>,
+    receiver: mpsc::UnboundedReceiver<Result<OwnedMessage, KafkaError>>,
+    pending: HashMap<u64, OwnedMessage>,
+    offsets: HashMap<(String, i32), PartitionOffsets>,
+    next_handle: u64,
+}
+
+impl KafkaSubscription {
+    fn commit(&mut self, record: &OwnedMessage) -> Result<(), KafkaError> {
+        let partition = (record.topic().to_string(), record.partition());
+        let Some(offsets) = self.offsets.get_mut(&partition) else {
+            return Ok(());
+        };
+        offsets.in_flight.remove(&record.offset());
+        if !self.commits {
+            return Ok(());
+        }
+        let mut list = TopicPartitionList::new();
+        list.add_partition_offset(
+            &partition.0,
+            partition.1,
+            Offset::Offset(offsets.committable()),
+        )?;
+        self.consumer.commit(&list, CommitMode::Async)
+    }
+}
+
+#[async_trait]
+impl BrokerSubscription for KafkaSubscription {
+    async fn next(&mut self) -> Option<Result<Delivery, anyhow::Error>> {
+        let record = match self.receiver.recv().await? {
+            Ok(record) => record,
+            Err(e) => return Some(Err(e.into())),
+        };
+        let offsets = self
+            .offsets
+            .entry((record.topic().to_string(), record.partition()))
+            .or_default();
+        offsets.in_flight.insert(record.offset());
+        offsets.last = offsets.last.max(record.offset());
+        self.next_handle += 1;
+        let delivery = Delivery {
+            handle: self.next_handle,
+            message_id: format!(
+                "{}:{}:{}",
+                record.topic(),
+                record.partition(),
+                record.offset()
+            ),
+            partition_key: record
+                .key()
+                .map(|key| String::from_utf8_lossy(key).into_owned())
+                .unwrap_or_default(),
+            payload: record.payload().unwrap_or_default().to_vec(),
+        };
+        self.pending.insert(self.next_handle, record);
+        Some(Ok(delivery))
+    }
+
+    async fn ack(&mut self, delivery: &Delivery) -> Result<(), anyhow::Error> {
+        if let Some(record) = self.pending.remove(&delivery.handle) {
+            self.commit(&record)?;
+        }
+        Ok(())
+    }
+
+    /// Kafka can't redeliver a single record, it is handed out again with its offset uncommitted.
+    async fn nack(&mut self, delivery: &Delivery) -> Result<(), anyhow::Error> {
+        if let Some(record) = self.pending.remove(&delivery.handle) {
+            self.sender
+                .send(Ok(record))
+                .map_err(|_| anyhow::anyhow!("Subscription is closed"))?;
+        }
+        Ok(())
+    }
+}
@@ -1,14 +1,25 @@
+pub mod broker;
+pub mod buffer;
 pub mod commands;
 mod errors;
 mod handlers;
+mod kafka_broker;
+mod long_poll;
+mod memory_broker;
 pub mod middlewares;
 mod models;
+mod ondc;
 mod openapi;
-mod pulsar_client;
+mod presence;
+mod producer_pool;
+pub mod pulsar_client;
+mod redis_broker;
 mod routes;
 mod schemas;
+mod sse;
 pub mod startup;
 pub mod telemetry;
+#[cfg(test)]
 mod tests;
 pub mod utils;
 pub mod websocket;
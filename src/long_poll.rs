@@ -0,0 +1,287 @@
+use crate::schemas::{WSKeyTrait, WebSocketParam};
+use crate::websocket::{
+    Activity, ClientAck, ClientInfo, CloseSession, Connect, Disconnect, Message, PayloadMeta,
+    Server, SessionActivity,
+};
+use actix::{
+    fut, Actor, ActorContext, ActorFutureExt, Addr, AsyncContext, Context, ContextFutureSpawner,
+    Handler, Message as ActixMessage, MessageResponse, Running, WrapFuture,
+};
+use actix_web_actors::ws;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Most messages a mailbox holds, the oldest are dropped beyond it.
+const MAX_QUEUED: usize = 1000;
+/// A mailbox nobody polled for this long is closed, `Server` buffers later messages of the key.
+const MAILBOX_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct Queued {
+    sequence: u64,
+    message_id: Option<Uuid>,
+    payload: String,
+}
+
+/// Messages handed to a poll request.
+#[derive(Default)]
+pub struct PollBatch {
+    pub messages: Vec<String>,
+    /// Sequence of the last message
+    pub cursor: Option<u64>,
+    /// Set when the mailbox was closed through the kick API
+    pub closed: Option<ws::CloseReason>,
+}
+
+/// Queue of a long-polling client, registered with `Server` like a `WebSocketSession`.
+/// Messages stay queued, and unacked, until a poll's cursor shows the client received them.
+pub struct PollMailbox {
+    id: String,
+    conn_id: Uuid,
+    user_id: Option<Uuid>,
+    business_id: Option<Uuid>,
+    last_seen: Option<u64>,
+    client: ClientInfo,
+    server_addr: Addr<Server>,
+    queue: VecDeque<Queued>,
+    waiter: Option<oneshot::Sender<PollBatch>>,
+    last_poll: Instant,
+    closed: Option<ws::CloseReason>,
+}
+
+impl PollMailbox {
+    pub fn new(
+        param: &WebSocketParam,
+        cursor: Option<u64>,
+        client: ClientInfo,
+        server_addr: Addr<Server>,
+    ) -> Self {
+        Self {
+            id: param.get_ws_key(),
+            conn_id: Uuid::new_v4(),
+            user_id: param.user_id,
+            business_id: param.business_id,
+            last_seen: cursor.or(param.last_seen),
+            client,
+            server_addr,
+            queue: VecDeque::new(),
+            waiter: None,
+            last_poll: Instant::now(),
+            closed: None,
+        }
+    }
+
+    /// Identifies the mailbox to the polls of the client, returned with every poll.
+    pub fn session(&self) -> Uuid {
+        self.conn_id
+    }
+
+    fn batch(&self) -> PollBatch {
+        PollBatch {
+            messages: self.queue.iter().map(|q| q.payload.clone()).collect(),
+            cursor: self.queue.back().map(|q| q.sequence),
+            closed: self.closed.clone(),
+        }
+    }
+
+    /// Drops and acks the messages the client has received.
+    fn release(&mut self, cursor: u64) {
+        while self.queue.front().is_some_and(|q| q.sequence <= cursor) {
+            if let Some(message_id) = self.queue.pop_front().and_then(|q| q.message_id) {
+                self.server_addr.do_send(ClientAck {
+                    conn_id: self.conn_id,
+                    message_id,
+                });
+            }
+        }
+    }
+
+    fn wake(&mut self) {
+        if let Some(waiter) = self.waiter.take() {
+            let _ = waiter.send(self.batch());
+        }
+    }
+
+    fn expire_when_idle(&self, ctx: &mut Context<Self>) {
+        ctx.run_interval(MAILBOX_IDLE_TIMEOUT / 2, |act, ctx| {
+            let waiting = act.waiter.as_ref().is_some_and(|w| !w.is_closed());
+            if !waiting && act.last_poll.elapsed() > MAILBOX_IDLE_TIMEOUT {
+                info!("Long poll client went away, disconnecting!");
+                ctx.stop();
+            }
+        });
+    }
+}
+
+impl Actor for PollMailbox {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.expire_when_idle(ctx);
+
+        let mailbox_addr = ctx.address();
+        self.server_addr
+            .send(Connect {
+                addr: mailbox_addr.clone().recipient(),
+                id: self.id.clone(),
+                conn_id: self.conn_id,
+                user_id: self.user_id,
+                business_id: self.business_id,
+                last_seen: self.last_seen,
+                client: self.client.clone(),
+                closer: mailbox_addr.recipient(),
+            })
+            .into_actor(self)
+            .then(|res, _act, ctx| {
+                if res.is_err() {
+                    ctx.stop();
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        self.server_addr.do_send(Disconnect {
+            id: self.id.clone(),
+            conn_id: self.conn_id,
+            user_id: self.user_id,
+            business_id: self.business_id,
+        });
+        Running::Stop
+    }
+}
+
+impl Handler<Message> for PollMailbox {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, _: &mut Self::Context) {
+        let meta = PayloadMeta::parse(&msg.0);
+        self.queue.push_back(Queued {
+            sequence: meta.sequence.unwrap_or_default(),
+            message_id: meta.message_id,
+            payload: msg.0,
+        });
+        if self.queue.len() > MAX_QUEUED {
+            warn!(
+                "Long poll mailbox of {} is full, dropping oldest message",
+                self.id
+            );
+            self.queue.pop_front();
+        }
+        self.wake();
+    }
+}
+
+impl Handler<CloseSession> for PollMailbox {
+    type Result = ();
+
+    fn handle(&mut self, msg: CloseSession, ctx: &mut Self::Context) {
+        info!("Closing long poll mailbox: {:?}", msg.0);
+        self.closed = Some(msg.0);
+        self.wake();
+        ctx.stop();
+    }
+}
+
+/// Waits for messages after `cursor`, answered at once when some are queued.
+/// A newer poll of the same mailbox takes over and the older one ends empty.
+#[derive(ActixMessage)]
+#[rtype(result = "PendingPoll")]
+pub struct Poll {
+    pub cursor: Option<u64>,
+}
+
+#[derive(MessageResponse)]
+pub struct PendingPoll(pub oneshot::Receiver<PollBatch>);
+
+impl Handler<Poll> for PollMailbox {
+    type Result = PendingPoll;
+
+    fn handle(&mut self, msg: Poll, _: &mut Self::Context) -> Self::Result {
+        self.last_poll = Instant::now();
+        self.server_addr.do_send(SessionActivity {
+            conn_id: self.conn_id,
+            activity: Activity::Heartbeat,
+        });
+        if let Some(cursor) = msg.cursor {
+            self.release(cursor);
+        }
+        let (sender, receiver) = oneshot::channel();
+        self.waiter = Some(sender);
+        if !self.queue.is_empty() {
+            self.wake();
+        }
+        PendingPoll(receiver)
+    }
+}
+
+/// Mailboxes by poll session, they outlive a single poll request. Each client polling a key,
+/// like a browser tab, gets its own.
+pub struct Mailboxes {
+    /// Key and address of each mailbox
+    mailboxes: HashMap<Uuid, (String, Addr<PollMailbox>)>,
+}
+
+impl Mailboxes {
+    pub fn new() -> Self {
+        Self {
+            mailboxes: HashMap::new(),
+        }
+    }
+}
+
+impl Default for Mailboxes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Actor for Mailboxes {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        // mailboxes stop on their own when idle or kicked
+        ctx.run_interval(MAILBOX_IDLE_TIMEOUT, |act, _| {
+            act.mailboxes.retain(|_, (_, addr)| addr.connected());
+        });
+    }
+}
+
+/// Returns the running mailbox of the poll session when it belongs to the key, else starts the
+/// given one under a new session.
+#[derive(ActixMessage)]
+#[rtype(result = "OpenedMailbox")]
+pub struct OpenMailbox {
+    pub session: Option<Uuid>,
+    pub mailbox: PollMailbox,
+}
+
+#[derive(MessageResponse)]
+pub struct OpenedMailbox {
+    pub session: Uuid,
+    pub addr: Addr<PollMailbox>,
+}
+
+impl Handler<OpenMailbox> for Mailboxes {
+    type Result = OpenedMailbox;
+
+    fn handle(&mut self, msg: OpenMailbox, _: &mut Self::Context) -> Self::Result {
+        let running = msg.session.and_then(|session| {
+            self.mailboxes
+                .get(&session)
+                .filter(|(id, addr)| *id == msg.mailbox.id && addr.connected())
+                .map(|(_, addr)| (session, addr.clone()))
+        });
+        if let Some((session, addr)) = running {
+            return OpenedMailbox { session, addr };
+        }
+        let session = msg.mailbox.session();
+        let id = msg.mailbox.id.clone();
+        let addr = msg.mailbox.start();
+        self.mailboxes.insert(session, (id, addr.clone()));
+        OpenedMailbox { session, addr }
+    }
+}
@@ -0,0 +1,172 @@
+use crate::broker::{
+    BrokerSubscription, Delivery, Destination, MessageBroker, MessageData, Source,
+};
+use async_trait::async_trait;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::error;
+
+/// Dead letters kept for inspection, older ones are dropped.
+const MAX_DEAD_LETTERS: usize = 1000;
+
+struct Envelope {
+    message_id: u64,
+    message: MessageData,
+}
+
+/// In-process broker backed by channels, for local development and tests without external services.
+/// Each topic has a single subscriber; publishing before anyone subscribes fails.
+#[derive(Default)]
+pub struct MemoryBroker {
+    topics: Mutex<HashMap<String, mpsc::UnboundedSender<Envelope>>>,
+    next_message_id: AtomicU64,
+    /// Message id and reason of the latest dead letters
+    dead_letters: Mutex<VecDeque<(String, String)>>,
+}
+
+impl MemoryBroker {
+    fn destination_topic(destination: Destination<'_>) -> String {
+        match destination {
+            Destination::Shared => "shared".to_string(),
+            Destination::Instance(instance_id) => format!("instance-{}", instance_id),
+            Destination::Inbound => "inbound".to_string(),
+            Destination::Presence => "presence".to_string(),
+        }
+    }
+
+    fn source_topic(source: &Source) -> String {
+        match source {
+            Source::Shared => Self::destination_topic(Destination::Shared),
+            Source::Instance(instance_id) => {
+                Self::destination_topic(Destination::Instance(instance_id))
+            }
+            Source::Presence(_) => Self::destination_topic(Destination::Presence),
+            Source::Inbound => Self::destination_topic(Destination::Inbound),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn is_subscribed(&self, source: &Source) -> bool {
+        self.topics
+            .lock()
+            .unwrap()
+            .contains_key(&Self::source_topic(source))
+    }
+
+    #[cfg(test)]
+    pub fn dead_letters(&self) -> Vec<(String, String)> {
+        self.dead_letters.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[async_trait]
+impl MessageBroker for MemoryBroker {
+    async fn publish(
+        &self,
+        destination: Destination<'_>,
+        message: MessageData,
+    ) -> Result<(), anyhow::Error> {
+        let topic = Self::destination_topic(destination);
+        let Some(sender) = self.topics.lock().unwrap().get(&topic).cloned() else {
+            anyhow::bail!("No subscriber for topic {}", topic);
+        };
+        let envelope = Envelope {
+            message_id: self.next_message_id.fetch_add(1, Ordering::Relaxed),
+            message,
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or_default();
+        match envelope.message.deliver_at {
+            Some(deliver_at) if deliver_at > now => {
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_millis((deliver_at - now) as u64)).await;
+                    let _ = sender.send(envelope);
+                });
+            }
+            _ => sender
+                .send(envelope)
+                .map_err(|_| anyhow::anyhow!("Subscriber for topic {} is closed", topic))?,
+        }
+        Ok(())
+    }
+
+    async fn subscribe(
+        &self,
+        source: Source,
+    ) -> Result<Box<dyn BrokerSubscription>, anyhow::Error> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.topics
+            .lock()
+            .unwrap()
+            .insert(Self::source_topic(&source), sender.clone());
+        Ok(Box::new(MemorySubscription {
+            sender,
+            receiver,
+            pending: HashMap::new(),
+            next_handle: 0,
+        }))
+    }
+
+    async fn dead_letter(&self, delivery: &Delivery, reason: &str) -> Result<(), anyhow::Error> {
+        error!(
+            "Dead letter message {} ({}): {}",
+            delivery.message_id,
+            reason,
+            String::from_utf8_lossy(&delivery.payload)
+        );
+        let mut dead_letters = self.dead_letters.lock().unwrap();
+        if dead_letters.len() >= MAX_DEAD_LETTERS {
+            dead_letters.pop_front();
+        }
+        dead_letters.push_back((delivery.message_id.clone(), reason.to_string()));
+        Ok(())
+    }
+
+    fn accepts_inbound(&self) -> bool {
+        true
+    }
+}
+
+pub struct MemorySubscription {
+    sender: mpsc::UnboundedSender<Envelope>,
+    receiver: mpsc::UnboundedReceiver<Envelope>,
+    pending: HashMap<u64, Envelope>,
+    next_handle: u64,
+}
+
+#[async_trait]
+impl BrokerSubscription for MemorySubscription {
+    async fn next(&mut self) -> Option<Result<Delivery, anyhow::Error>> {
+        let envelope = self.receiver.recv().await?;
+        self.next_handle += 1;
+        let delivery = serde_json::to_vec(&envelope.message)
+            .map(|payload| Delivery {
+                handle: self.next_handle,
+                message_id: envelope.message_id.to_string(),
+                partition_key: envelope.message.partition_key.clone(),
+                payload,
+            })
+            .map_err(anyhow::Error::from);
+        self.pending.insert(self.next_handle, envelope);
+        Some(delivery)
+    }
+
+    async fn ack(&mut self, delivery: &Delivery) -> Result<(), anyhow::Error> {
+        self.pending.remove(&delivery.handle);
+        Ok(())
+    }
+
+    async fn nack(&mut self, delivery: &Delivery) -> Result<(), anyhow::Error> {
+        if let Some(envelope) = self.pending.remove(&delivery.handle) {
+            self.sender
+                .send(envelope)
+                .map_err(|_| anyhow::anyhow!("Subscription is closed"))?;
+        }
+        Ok(())
+    }
+}
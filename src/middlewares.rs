@@ -5,12 +5,13 @@ use actix_http::header::UPGRADE;
 use actix_http::{h1, Payload};
 use actix_web::body::{self, BoxBody};
 use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
-use actix_web::{http, web, Error};
+use actix_web::{http, web, Error, HttpMessage};
 use futures::future::LocalBoxFuture;
 use std::cell::RefCell;
 use std::future::{ready, Ready};
 use std::rc::Rc;
-use tracing::instrument;
+use tracing::{instrument, Span};
+use tracing_actix_web::{DefaultRootSpanBuilder, RootSpanBuilder};
 
 pub struct AuthMiddleware<S> {
     service: Rc<S>,
@@ -50,8 +51,8 @@ where
             return Box::pin(async { Ok(ServiceResponse::from_err(json_error, request)) });
         }
 
-        let _ = match decode_token(token.unwrap(), jwt_secret) {
-            Ok(id) => id,
+        let claims = match decode_token(token.unwrap(), jwt_secret) {
+            Ok(claims) => claims,
             Err(e) => {
                 return Box::pin(async move {
                     let (request, _pl) = req.into_parts();
@@ -63,6 +64,7 @@ where
             }
         };
 
+        req.extensions_mut().insert(claims);
         let fut = self.service.call(req);
         Box::pin(async move {
             let res = fut.await?;
@@ -122,7 +124,9 @@ where
         let is_on_search = req.path().ends_with("on_search");
         let is_non_json_req_res =
             req.path().contains("/docs/") || req.path().contains("/api-docs/");
-        if is_websocket || is_non_json_req_res {
+        // an event stream never ends, buffering it would hold back every event
+        let is_event_stream = req.path() == "/events";
+        if is_websocket || is_non_json_req_res || is_event_stream {
             Box::pin(async move {
                 let fut = svc.call(req).await?;
                 Ok(fut)
@@ -135,6 +139,9 @@ where
                     req.set_payload(bytes_to_payload(web::Bytes::from(request_str)));
                 }
                 let fut = svc.call(req).await?;
+                if is_streaming(fut.response()) {
+                    return Ok(fut);
+                }
 
                 let (req, res) = fut.into_parts();
                 let (res, body) = res.into_parts();
@@ -155,6 +162,13 @@ where
     }
 }
 
+fn is_streaming<B>(res: &actix_web::HttpResponse<B>) -> bool {
+    res.headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/event-stream"))
+}
+
 pub struct SaveRequestResponse;
 
 impl<S> Transform<S, ServiceRequest> for SaveRequestResponse
@@ -174,3 +188,28 @@ where
         }))
     }
 }
+
+/// Root span of `TracingLogger` recording the path without the query, which may carry a token.
+pub struct PathOnlyRootSpan;
+
+impl RootSpanBuilder for PathOnlyRootSpan {
+    fn on_request_start(request: &ServiceRequest) -> Span {
+        tracing::info_span!(
+            "HTTP request",
+            http.method = %request.method(),
+            http.route = %request.match_pattern().unwrap_or_default(),
+            http.target = %request.path(),
+            http.status_code = tracing::field::Empty,
+            otel.status_code = tracing::field::Empty,
+            exception.message = tracing::field::Empty,
+            exception.details = tracing::field::Empty,
+        )
+    }
+
+    fn on_request_end<B: body::MessageBody>(
+        span: Span,
+        outcome: &Result<ServiceResponse<B>, Error>,
+    ) {
+        DefaultRootSpanBuilder::on_request_end(span, outcome);
+    }
+}
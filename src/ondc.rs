@@ -0,0 +1,603 @@
+use crate::websocket::WebSocketActionType;
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+use std::fmt;
+
+/// A problem with one field of a payload, `field` is a path like `message.order.items[0].id`.
+#[derive(Debug)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+/// Rules serde can't express, errors are collected instead of stopping at the first.
+trait Check {
+    fn check(&self, path: &str, errors: &mut Vec<FieldError>);
+}
+
+fn require_non_empty(value: &str, field: String, errors: &mut Vec<FieldError>) {
+    if value.trim().is_empty() {
+        errors.push(FieldError::new(field, "must not be empty"));
+    }
+}
+
+fn check_all<T: Check>(items: &[T], path: &str, errors: &mut Vec<FieldError>) {
+    if items.is_empty() {
+        errors.push(FieldError::new(path, "must not be empty"));
+    }
+    for (index, item) in items.iter().enumerate() {
+        item.check(&format!("{}[{}]", path, index), errors);
+    }
+}
+
+/// Beckn context shared by every callback.
+#[derive(Deserialize, Debug)]
+pub struct Context {
+    pub domain: String,
+    pub action: String,
+    pub core_version: String,
+    pub bap_id: String,
+    pub bap_uri: String,
+    pub bpp_uri: Option<String>,
+    pub transaction_id: String,
+    pub message_id: String,
+    /// Only parsed, to reject timestamps that aren't RFC 3339
+    #[allow(dead_code)]
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Context {
+    fn check(&self, action: &str, errors: &mut Vec<FieldError>) {
+        if self.action != action {
+            errors.push(FieldError::new(
+                "context.action",
+                format!("expected {}, found {}", action, self.action),
+            ));
+        }
+        require_non_empty(&self.domain, "context.domain".to_string(), errors);
+        require_non_empty(
+            &self.core_version,
+            "context.core_version".to_string(),
+            errors,
+        );
+        require_non_empty(&self.bap_id, "context.bap_id".to_string(), errors);
+        require_non_empty(
+            &self.transaction_id,
+            "context.transaction_id".to_string(),
+            errors,
+        );
+        require_non_empty(&self.message_id, "context.message_id".to_string(), errors);
+        let uris = [
+            ("bap_uri", Some(&self.bap_uri)),
+            ("bpp_uri", self.bpp_uri.as_ref()),
+        ];
+        for (field, uri) in uris {
+            if let Some(uri) = uri.filter(|uri| !uri.starts_with("http")) {
+                errors.push(FieldError::new(
+                    format!("context.{}", field),
+                    format!("{} is not an http(s) URI", uri),
+                ));
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BecknError {
+    pub code: String,
+}
+
+/// `on_*` callback, `message` may be left out when it reports an `error`.
+#[derive(Deserialize, Debug)]
+struct Callback<M> {
+    context: Context,
+    message: Option<M>,
+    error: Option<BecknError>,
+}
+
+impl<M: Check> Callback<M> {
+    fn check(&self, action: &str, errors: &mut Vec<FieldError>) {
+        self.context.check(action, errors);
+        match (&self.message, &self.error) {
+            (Some(message), _) => message.check("message", errors),
+            (None, Some(error)) => require_non_empty(&error.code, "error.code".to_string(), errors),
+            (None, None) => errors.push(FieldError::new(
+                "message",
+                "missing field `message`, required unless `error` is set",
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Price {
+    pub currency: String,
+    /// Decimal as a string, e.g. `"120.50"`
+    pub value: String,
+}
+
+impl Check for Price {
+    fn check(&self, path: &str, errors: &mut Vec<FieldError>) {
+        if self.currency.len() != 3 {
+            errors.push(FieldError::new(
+                format!("{}.currency", path),
+                "must be an ISO 4217 code",
+            ));
+        }
+        if self.value.parse::<f64>().is_err() {
+            errors.push(FieldError::new(
+                format!("{}.value", path),
+                format!("{} is not a decimal", self.value),
+            ));
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CatalogItem {
+    pub id: String,
+    pub price: Option<Price>,
+}
+
+impl Check for CatalogItem {
+    fn check(&self, path: &str, errors: &mut Vec<FieldError>) {
+        require_non_empty(&self.id, format!("{}.id", path), errors);
+        if let Some(price) = &self.price {
+            price.check(&format!("{}.price", path), errors);
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CatalogProvider {
+    pub id: String,
+    #[serde(default)]
+    pub items: Vec<CatalogItem>,
+}
+
+impl Check for CatalogProvider {
+    fn check(&self, path: &str, errors: &mut Vec<FieldError>) {
+        require_non_empty(&self.id, format!("{}.id", path), errors);
+        for (index, item) in self.items.iter().enumerate() {
+            item.check(&format!("{}.items[{}]", path, index), errors);
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Catalog {
+    #[serde(rename = "bpp/providers")]
+    pub providers: Vec<CatalogProvider>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OnSearchMessage {
+    pub catalog: Catalog,
+}
+
+impl Check for OnSearchMessage {
+    fn check(&self, path: &str, errors: &mut Vec<FieldError>) {
+        let path = format!("{}.catalog.bpp/providers", path);
+        for (index, provider) in self.catalog.providers.iter().enumerate() {
+            provider.check(&format!("{}[{}]", path, index), errors);
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OrderProvider {
+    pub id: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Quantity {
+    pub count: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OrderItem {
+    pub id: String,
+    pub quantity: Option<Quantity>,
+}
+
+impl Check for OrderItem {
+    fn check(&self, path: &str, errors: &mut Vec<FieldError>) {
+        require_non_empty(&self.id, format!("{}.id", path), errors);
+        if self.quantity.as_ref().is_some_and(|q| q.count == 0) {
+            errors.push(FieldError::new(
+                format!("{}.quantity.count", path),
+                "must be at least 1",
+            ));
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Fulfillment {
+    pub id: String,
+}
+
+impl Check for Fulfillment {
+    fn check(&self, path: &str, errors: &mut Vec<FieldError>) {
+        require_non_empty(&self.id, format!("{}.id", path), errors);
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Breakup {
+    pub title: String,
+    pub price: Price,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Quote {
+    pub price: Price,
+    #[serde(default)]
+    pub breakup: Vec<Breakup>,
+}
+
+impl Check for Quote {
+    fn check(&self, path: &str, errors: &mut Vec<FieldError>) {
+        self.price.check(&format!("{}.price", path), errors);
+        for (index, breakup) in self.breakup.iter().enumerate() {
+            let path = format!("{}.breakup[{}]", path, index);
+            require_non_empty(&breakup.title, format!("{}.title", path), errors);
+            breakup.price.check(&format!("{}.price", path), errors);
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Billing {
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SelectOrder {
+    pub provider: OrderProvider,
+    pub items: Vec<OrderItem>,
+    #[serde(default)]
+    pub fulfillments: Vec<Fulfillment>,
+    pub quote: Quote,
+}
+
+impl Check for SelectOrder {
+    fn check(&self, path: &str, errors: &mut Vec<FieldError>) {
+        require_non_empty(&self.provider.id, format!("{}.provider.id", path), errors);
+        check_all(&self.items, &format!("{}.items", path), errors);
+        for (index, fulfillment) in self.fulfillments.iter().enumerate() {
+            fulfillment.check(&format!("{}.fulfillments[{}]", path, index), errors);
+        }
+        self.quote.check(&format!("{}.quote", path), errors);
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InitOrder {
+    pub provider: OrderProvider,
+    pub items: Vec<OrderItem>,
+    pub billing: Billing,
+    pub fulfillments: Vec<Fulfillment>,
+    pub quote: Quote,
+}
+
+impl Check for InitOrder {
+    fn check(&self, path: &str, errors: &mut Vec<FieldError>) {
+        require_non_empty(&self.provider.id, format!("{}.provider.id", path), errors);
+        check_all(&self.items, &format!("{}.items", path), errors);
+        require_non_empty(&self.billing.name, format!("{}.billing.name", path), errors);
+        check_all(
+            &self.fulfillments,
+            &format!("{}.fulfillments", path),
+            errors,
+        );
+        self.quote.check(&format!("{}.quote", path), errors);
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ConfirmOrder {
+    pub id: String,
+    pub state: String,
+    pub provider: OrderProvider,
+    pub items: Vec<OrderItem>,
+    pub billing: Billing,
+    pub fulfillments: Vec<Fulfillment>,
+    pub quote: Quote,
+    pub payment: Value,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Check for ConfirmOrder {
+    fn check(&self, path: &str, errors: &mut Vec<FieldError>) {
+        require_non_empty(&self.id, format!("{}.id", path), errors);
+        require_non_empty(&self.state, format!("{}.state", path), errors);
+        require_non_empty(&self.provider.id, format!("{}.provider.id", path), errors);
+        check_all(&self.items, &format!("{}.items", path), errors);
+        require_non_empty(&self.billing.name, format!("{}.billing.name", path), errors);
+        check_all(
+            &self.fulfillments,
+            &format!("{}.fulfillments", path),
+            errors,
+        );
+        self.quote.check(&format!("{}.quote", path), errors);
+        if !self.payment.is_object() {
+            errors.push(FieldError::new(
+                format!("{}.payment", path),
+                "must be an object",
+            ));
+        }
+        if self.updated_at < self.created_at {
+            errors.push(FieldError::new(
+                format!("{}.updated_at", path),
+                "is before created_at",
+            ));
+        }
+    }
+}
+
+/// Order of `on_status`, `on_update` and `on_cancel`, which may carry only the changed parts.
+#[derive(Deserialize, Debug)]
+pub struct OrderState {
+    pub id: String,
+    pub state: String,
+    #[serde(default)]
+    pub items: Vec<OrderItem>,
+    #[serde(default)]
+    pub fulfillments: Vec<Fulfillment>,
+    pub quote: Option<Quote>,
+}
+
+impl Check for OrderState {
+    fn check(&self, path: &str, errors: &mut Vec<FieldError>) {
+        require_non_empty(&self.id, format!("{}.id", path), errors);
+        require_non_empty(&self.state, format!("{}.state", path), errors);
+        for (index, item) in self.items.iter().enumerate() {
+            item.check(&format!("{}.items[{}]", path, index), errors);
+        }
+        for (index, fulfillment) in self.fulfillments.iter().enumerate() {
+            fulfillment.check(&format!("{}.fulfillments[{}]", path, index), errors);
+        }
+        if let Some(quote) = &self.quote {
+            quote.check(&format!("{}.quote", path), errors);
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OrderMessage<O> {
+    pub order: O,
+}
+
+impl<O: Check> Check for OrderMessage<O> {
+    fn check(&self, path: &str, errors: &mut Vec<FieldError>) {
+        self.order.check(&format!("{}.order", path), errors);
+    }
+}
+
+/// IGM issue as sent in `on_issue` and `on_issue_status`.
+#[derive(Deserialize, Debug)]
+pub struct Issue {
+    pub id: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct IssueMessage {
+    pub issue: Issue,
+}
+
+impl Check for IssueMessage {
+    fn check(&self, path: &str, errors: &mut Vec<FieldError>) {
+        require_non_empty(&self.issue.id, format!("{}.issue.id", path), errors);
+        if self.issue.updated_at < self.issue.created_at {
+            errors.push(FieldError::new(
+                format!("{}.issue.updated_at", path),
+                "is before created_at",
+            ));
+        }
+    }
+}
+
+fn validate<M: DeserializeOwned + Check>(action: &str, data: &Value) -> Vec<FieldError> {
+    let callback: Callback<M> = match serde_path_to_error::deserialize(data) {
+        Ok(callback) => callback,
+        Err(e) => {
+            let field = match e.path().to_string() {
+                path if path == "." => "data".to_string(),
+                path => path,
+            };
+            return vec![FieldError::new(field, e.into_inner().to_string())];
+        }
+    };
+    let mut errors = vec![];
+    callback.check(action, &mut errors);
+    errors
+}
+
+/// Checks `data` against the ONDC callback of the action, `Info` payloads are free-form.
+pub fn validate_payload(action_type: WebSocketActionType, data: &Value) -> Vec<FieldError> {
+    match action_type {
+        WebSocketActionType::Search => validate::<OnSearchMessage>("on_search", data),
+        WebSocketActionType::Select => validate::<OrderMessage<SelectOrder>>("on_select", data),
+        WebSocketActionType::Init => validate::<OrderMessage<InitOrder>>("on_init", data),
+        WebSocketActionType::Confirm => validate::<OrderMessage<ConfirmOrder>>("on_confirm", data),
+        WebSocketActionType::Update => validate::<OrderMessage<OrderState>>("on_update", data),
+        WebSocketActionType::Status => validate::<OrderMessage<OrderState>>("on_status", data),
+        WebSocketActionType::Cancel => validate::<OrderMessage<OrderState>>("on_cancel", data),
+        WebSocketActionType::Issue => validate::<IssueMessage>("on_issue", data),
+        WebSocketActionType::IssueStatus => validate::<IssueMessage>("on_issue_status", data),
+        WebSocketActionType::Info => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn context(action: &str) -> Value {
+        json!({
+            "domain": "ONDC:RET10",
+            "action": action,
+            "core_version": "1.2.0",
+            "bap_id": "buyer.example.com",
+            "bap_uri": "https://buyer.example.com/ondc",
+            "bpp_uri": "https://seller.example.com/ondc",
+            "transaction_id": "T1",
+            "message_id": "M1",
+            "timestamp": "2024-05-01T10:00:00.000Z"
+        })
+    }
+
+    fn price(value: &str) -> Value {
+        json!({"currency": "INR", "value": value})
+    }
+
+    fn fields(errors: &[FieldError]) -> Vec<&str> {
+        errors.iter().map(|e| e.field.as_str()).collect()
+    }
+
+    #[test]
+    fn valid_on_search_passes() {
+        let data = json!({
+            "context": context("on_search"),
+            "message": {"catalog": {"bpp/providers": [
+                {"id": "P1", "items": [{"id": "I1", "price": price("120.50")}]}
+            ]}}
+        });
+        assert!(validate_payload(WebSocketActionType::Search, &data).is_empty());
+    }
+
+    #[test]
+    fn context_errors_are_collected() {
+        let mut context = context("on_select");
+        context["bap_uri"] = json!("ftp://buyer.example.com");
+        context["transaction_id"] = json!(" ");
+        let data = json!({"context": context, "error": {"code": "30004"}});
+        let errors = validate_payload(WebSocketActionType::Search, &data);
+        assert_eq!(
+            fields(&errors),
+            vec![
+                "context.action",
+                "context.transaction_id",
+                "context.bap_uri"
+            ]
+        );
+    }
+
+    #[test]
+    fn nested_errors_carry_their_path() {
+        let data = json!({
+            "context": context("on_select"),
+            "message": {"order": {
+                "provider": {"id": "P1"},
+                "items": [{"id": "I1", "quantity": {"count": 0}}],
+                "quote": {
+                    "price": {"currency": "RUPEE", "value": "10"},
+                    "breakup": [{"title": "", "price": price("ten")}]
+                }
+            }}
+        });
+        let errors = validate_payload(WebSocketActionType::Select, &data);
+        assert_eq!(
+            fields(&errors),
+            vec![
+                "message.order.items[0].quantity.count",
+                "message.order.quote.price.currency",
+                "message.order.quote.breakup[0].title",
+                "message.order.quote.breakup[0].price.value",
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_required_lists_are_rejected() {
+        let data = json!({
+            "context": context("on_init"),
+            "message": {"order": {
+                "provider": {"id": "P1"},
+                "items": [],
+                "billing": {"name": "Buyer"},
+                "fulfillments": [],
+                "quote": {"price": price("10")}
+            }}
+        });
+        let errors = validate_payload(WebSocketActionType::Init, &data);
+        assert_eq!(
+            fields(&errors),
+            vec!["message.order.items", "message.order.fulfillments"]
+        );
+    }
+
+    #[test]
+    fn type_errors_point_at_the_field() {
+        let data = json!({
+            "context": context("on_status"),
+            "message": {"order": {"id": "O1", "state": "Accepted", "items": [
+                {"id": "I1", "quantity": {"count": "two"}}
+            ]}}
+        });
+        let errors = validate_payload(WebSocketActionType::Status, &data);
+        assert_eq!(
+            fields(&errors),
+            vec!["message.order.items[0].quantity.count"]
+        );
+    }
+
+    #[test]
+    fn message_is_required_unless_an_error_is_set() {
+        let data = json!({"context": context("on_cancel")});
+        let errors = validate_payload(WebSocketActionType::Cancel, &data);
+        assert_eq!(fields(&errors), vec!["message"]);
+
+        let data = json!({"context": context("on_cancel"), "error": {"code": "40000"}});
+        assert!(validate_payload(WebSocketActionType::Cancel, &data).is_empty());
+    }
+
+    #[test]
+    fn timestamps_are_checked_in_order() {
+        let data = json!({
+            "context": context("on_issue"),
+            "message": {"issue": {
+                "id": "ISSUE1",
+                "created_at": "2024-05-02T10:00:00Z",
+                "updated_at": "2024-05-01T10:00:00Z"
+            }}
+        });
+        let errors = validate_payload(WebSocketActionType::Issue, &data);
+        assert_eq!(fields(&errors), vec!["message.issue.updated_at"]);
+
+        let mut context = context("on_issue_status");
+        context["timestamp"] = json!("yesterday");
+        let errors = validate_payload(
+            WebSocketActionType::IssueStatus,
+            &json!({"context": context}),
+        );
+        assert_eq!(fields(&errors), vec!["context.timestamp"]);
+    }
+
+    #[test]
+    fn info_is_free_form() {
+        assert!(validate_payload(WebSocketActionType::Info, &json!("anything")).is_empty());
+    }
+}
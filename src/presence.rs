@@ -0,0 +1,389 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::broker::{Backoff, Destination, MessageBroker, MessageData, Source};
+use crate::websocket::{MessageTarget, MessageToClient};
+
+/// How often an instance announces it is alive.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// Keys of an instance that sent no heartbeat for this long are no longer routed to it.
+const INSTANCE_TTL: Duration = Duration::from_secs(30);
+
+/// Published on the presence topic by every instance.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PresenceEvent {
+    /// The instance gained its first or lost its last connection for a key
+    Key {
+        key: String,
+        instance_id: String,
+        incarnation: Uuid,
+        online: bool,
+    },
+    /// Sent every `HEARTBEAT_INTERVAL`, a new `incarnation` means the instance restarted
+    Heartbeat {
+        instance_id: String,
+        incarnation: Uuid,
+        /// Unix millis, replayed heartbeats age from when they were sent
+        sent_at: i64,
+    },
+}
+
+impl PresenceEvent {
+    /// Compaction key, the presence topic keeps the latest event of each. Every owner of a key
+    /// keeps its own event.
+    fn partition_key(&self) -> String {
+        match self {
+            PresenceEvent::Key {
+                key, instance_id, ..
+            } => format!("{}#{}", key, instance_id),
+            PresenceEvent::Heartbeat { instance_id, .. } => format!("heartbeat#{}", instance_id),
+        }
+    }
+}
+
+/// Sends the presence changes of this instance's `Server` to the presence topic.
+#[derive(Clone)]
+pub struct PresenceNotifier {
+    pub instance_id: String,
+    /// Identifies this run of the instance
+    pub incarnation: Uuid,
+    pub sender: UnboundedSender<PresenceEvent>,
+}
+
+impl PresenceNotifier {
+    pub fn notify(&self, key: &str, online: bool) {
+        self.send(PresenceEvent::Key {
+            key: key.to_string(),
+            instance_id: self.instance_id.clone(),
+            incarnation: self.incarnation,
+            online,
+        });
+    }
+
+    fn heartbeat(&self) {
+        self.send(PresenceEvent::Heartbeat {
+            instance_id: self.instance_id.clone(),
+            incarnation: self.incarnation,
+            sent_at: Utc::now().timestamp_millis(),
+        });
+    }
+
+    fn send(&self, event: PresenceEvent) {
+        if let Err(err) = self.sender.send(event) {
+            error!("Failed to queue presence event: {:?}", err);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Directory {
+    /// Instances holding each key, with the incarnation that announced it
+    owners: HashMap<String, HashMap<String, Uuid>>,
+    /// Incarnation and send time of the last heartbeat of each instance
+    instances: HashMap<String, (Uuid, i64)>,
+}
+
+/// Whether a heartbeat sent at `sent_at` is recent enough for its instance to count as alive.
+fn is_recent(sent_at: i64) -> bool {
+    Utc::now().timestamp_millis() - sent_at <= INSTANCE_TTL.as_millis() as i64
+}
+
+impl Directory {
+    fn is_live(&self, instance_id: &str, incarnation: &Uuid) -> bool {
+        self.instances
+            .get(instance_id)
+            .is_some_and(|(live, sent_at)| live == incarnation && is_recent(*sent_at))
+    }
+
+    fn live_owners<'a>(
+        &'a self,
+        owners: &'a HashMap<String, Uuid>,
+    ) -> impl Iterator<Item = String> + 'a {
+        owners
+            .iter()
+            .filter(|(instance_id, incarnation)| self.is_live(instance_id, incarnation))
+            .map(|(instance_id, _)| instance_id.clone())
+    }
+}
+
+/// Which instances hold the sessions of each WebSocket key, built from the presence topic.
+/// A key may be held by several instances, owners whose instance stopped sending heartbeats
+/// or restarted are left out.
+#[derive(Clone, Default)]
+pub struct PresenceDirectory {
+    inner: Arc<RwLock<Directory>>,
+}
+
+impl PresenceDirectory {
+    pub fn apply(&self, event: PresenceEvent) {
+        let mut directory = self.inner.write().unwrap();
+        match event {
+            PresenceEvent::Key {
+                key,
+                instance_id,
+                incarnation,
+                online: true,
+            } => {
+                directory
+                    .owners
+                    .entry(key)
+                    .or_default()
+                    .insert(instance_id, incarnation);
+            }
+            PresenceEvent::Key {
+                key,
+                instance_id,
+                incarnation,
+                online: false,
+            } => {
+                if let Some(owners) = directory.owners.get_mut(&key) {
+                    if owners.get(&instance_id) == Some(&incarnation) {
+                        owners.remove(&instance_id);
+                    }
+                    if owners.is_empty() {
+                        directory.owners.remove(&key);
+                    }
+                }
+            }
+            PresenceEvent::Heartbeat {
+                instance_id,
+                incarnation,
+                sent_at,
+            } => {
+                let restarted = directory
+                    .instances
+                    .insert(instance_id.clone(), (incarnation, sent_at))
+                    .is_some_and(|(previous, _)| previous != incarnation);
+                if restarted {
+                    // keys of the previous run were never announced offline
+                    directory.owners.retain(|_, owners| {
+                        owners.retain(|id, owner| *id != instance_id || *owner == incarnation);
+                        !owners.is_empty()
+                    });
+                }
+            }
+        }
+    }
+
+    /// Forgets instances that stopped sending heartbeats, with the keys they held.
+    pub fn expire(&self) {
+        let mut directory = self.inner.write().unwrap();
+        directory
+            .instances
+            .retain(|_, (_, sent_at)| is_recent(*sent_at));
+        let Directory { owners, instances } = &mut *directory;
+        owners.retain(|_, owners| {
+            owners.retain(|instance_id, incarnation| {
+                instances
+                    .get(instance_id)
+                    .is_some_and(|(live, _)| live == incarnation)
+            });
+            !owners.is_empty()
+        });
+    }
+
+    /// Instances a message has to be published to, empty when no owner is known.
+    pub fn owners_for(&self, msg: &MessageToClient) -> HashSet<String> {
+        self.owners_of(msg.id.as_deref(), msg.target.as_ref())
+    }
+
+    /// Instances holding the key or the keys of the target, every known owner when neither is set.
+    pub fn owners_of(&self, id: Option<&str>, target: Option<&MessageTarget>) -> HashSet<String> {
+        let directory = self.inner.read().unwrap();
+        if let (None, Some(id)) = (target, id) {
+            return directory
+                .owners
+                .get(id)
+                .into_iter()
+                .flat_map(|owners| directory.live_owners(owners))
+                .collect();
+        }
+        directory
+            .owners
+            .iter()
+            .filter(|(key, _)| match target {
+                Some(target) => key_matches(key, target),
+                None => true,
+            })
+            .flat_map(|(_, owners)| directory.live_owners(owners))
+            .collect()
+    }
+}
+
+/// Matches a `user_id#business_id#device_id` key against a fan-out target.
+fn key_matches(key: &str, target: &MessageTarget) -> bool {
+    let mut parts = key.split('#');
+    let (user_id, business_id) = (parts.next(), parts.next());
+    match target {
+        MessageTarget::Business { business_id: b } => business_id == Some(b.to_string().as_str()),
+        MessageTarget::User { user_id: u } => user_id == Some(u.to_string().as_str()),
+        MessageTarget::UserBusiness {
+            user_id: u,
+            business_id: b,
+        } => user_id == Some(u.to_string().as_str()) && business_id == Some(b.to_string().as_str()),
+        // subscriptions aren't announced, every instance with a session of the business may hold one
+        MessageTarget::Transaction { business_id: b, .. } => {
+            business_id == Some(b.to_string().as_str())
+        }
+    }
+}
+
+/// Publishes this instance's presence changes, keyed by WebSocket key and instance so the topic can
+/// be compacted, and its heartbeats, and replays the presence history of all instances into the
+/// directory.
+pub fn start_presence(
+    broker: Arc<dyn MessageBroker>,
+    instance_id: &str,
+    directory: PresenceDirectory,
+) -> PresenceNotifier {
+    let source = Source::Presence(instance_id.to_string());
+    let subscriber = broker.clone();
+    let expiring = directory.clone();
+    tokio::spawn(async move {
+        let mut backoff = Backoff::default();
+        loop {
+            match subscriber.subscribe(source.clone()).await {
+                Ok(mut subscription) => {
+                    backoff.reset();
+                    while let Some(result) = subscription.next().await {
+                        match result {
+                            Ok(delivery) => {
+                                let event = delivery.message_data().ok().and_then(|message_data| {
+                                    serde_json::from_str::<PresenceEvent>(&message_data.data).ok()
+                                });
+                                match event {
+                                    Some(event) => directory.apply(event),
+                                    None => warn!("Invalid presence event"),
+                                }
+                                if let Err(e) = subscription.ack(&delivery).await {
+                                    error!("Failed to acknowledge presence event: {:?}", e);
+                                }
+                            }
+                            Err(e) => {
+                                error!("Failed to receive presence event: {:?}", e);
+                            }
+                        }
+                    }
+                    warn!("Presence subscription ended, resubscribing");
+                }
+                Err(e) => {
+                    error!("Failed to subscribe to presence events: {:?}", e);
+                }
+            }
+            tokio::time::sleep(backoff.next_delay()).await;
+        }
+    });
+
+    let (sender, mut receiver) = mpsc::unbounded_channel::<PresenceEvent>();
+    tokio::spawn(async move {
+        while let Some(event) = receiver.recv().await {
+            let message = MessageData {
+                partition_key: event.partition_key(),
+                data: serde_json::to_string(&event).unwrap(),
+                deliver_at: None,
+                control: false,
+            };
+            if let Err(e) = broker.publish(Destination::Presence, message).await {
+                error!("Failed to publish presence event: {:?}", e);
+            }
+        }
+    });
+    let notifier = PresenceNotifier {
+        instance_id: instance_id.to_string(),
+        incarnation: Uuid::new_v4(),
+        sender,
+    };
+    let heartbeat = notifier.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            interval.tick().await;
+            heartbeat.heartbeat();
+            expiring.expire();
+        }
+    });
+    notifier
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn online(key: &str, instance_id: &str, incarnation: Uuid) -> PresenceEvent {
+        PresenceEvent::Key {
+            key: key.to_string(),
+            instance_id: instance_id.to_string(),
+            incarnation,
+            online: true,
+        }
+    }
+
+    fn heartbeat(instance_id: &str, incarnation: Uuid, sent_at: i64) -> PresenceEvent {
+        PresenceEvent::Heartbeat {
+            instance_id: instance_id.to_string(),
+            incarnation,
+            sent_at,
+        }
+    }
+
+    /// What a compacted topic hands to an instance subscribing later.
+    fn compact(history: Vec<PresenceEvent>) -> Vec<PresenceEvent> {
+        let mut latest: Vec<PresenceEvent> = Vec::new();
+        for event in history {
+            latest.retain(|kept| kept.partition_key() != event.partition_key());
+            latest.push(event);
+        }
+        latest
+    }
+
+    #[test]
+    fn replayed_history_keeps_every_owner_of_a_key() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Utc::now().timestamp_millis();
+        let history = vec![
+            heartbeat("a", a, now),
+            heartbeat("b", b, now),
+            online("key", "a", a),
+            online("key", "b", b),
+        ];
+
+        let directory = PresenceDirectory::default();
+        for event in compact(history) {
+            directory.apply(event);
+        }
+
+        let owners = directory.owners_of(Some("key"), None);
+        assert_eq!(owners, HashSet::from(["a".to_string(), "b".to_string()]));
+    }
+
+    #[test]
+    fn replayed_stale_heartbeat_is_not_live() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Utc::now().timestamp_millis();
+        let stale = now - INSTANCE_TTL.as_millis() as i64 - 1;
+        let history = vec![
+            heartbeat("a", a, now),
+            heartbeat("b", b, stale),
+            online("key", "a", a),
+            online("key", "b", b),
+        ];
+
+        let directory = PresenceDirectory::default();
+        for event in compact(history) {
+            directory.apply(event);
+        }
+
+        let owners = directory.owners_of(Some("key"), None);
+        assert_eq!(owners, HashSet::from(["a".to_string()]));
+        directory.expire();
+        assert!(!directory.inner.read().unwrap().instances.contains_key("b"));
+    }
+}
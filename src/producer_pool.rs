@@ -0,0 +1,26 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use tokio::sync::{Mutex, MutexGuard};
+
+/// Several producers for one topic. A partition key always maps to the same shard, so messages
+/// of a key keep their order while different keys are published concurrently.
+pub struct ProducerPool<P> {
+    shards: Vec<Mutex<P>>,
+}
+
+impl<P> ProducerPool<P> {
+    pub fn new(producers: Vec<P>) -> Self {
+        assert!(!producers.is_empty(), "a producer pool needs a producer");
+        Self {
+            shards: producers.into_iter().map(Mutex::new).collect(),
+        }
+    }
+
+    /// Locks the producer owning the partition key.
+    pub async fn get(&self, partition_key: &str) -> MutexGuard<'_, P> {
+        let mut hasher = DefaultHasher::new();
+        partition_key.hash(&mut hasher);
+        let shard = (hasher.finish() % self.shards.len() as u64) as usize;
+        self.shards[shard].lock().await
+    }
+}
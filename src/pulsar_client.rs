@@ -1,19 +1,19 @@
-use crate::websocket::{MessageToClient, Server, SessionExists};
-use actix::Addr;
-use actix_web::web::Data;
+use crate::broker::{
+    BrokerSubscription, Delivery, Destination, MessageBroker, MessageData, Source,
+};
+use crate::producer_pool::ProducerPool;
+use async_trait::async_trait;
 use futures::TryStreamExt;
 use pulsar::{
-    producer, Consumer, DeserializeMessage, Error as PulsarError, Payload, Producer, Pulsar,
+    consumer::{self, ConsumerOptions, InitialPosition},
+    producer::{self, ProducerOptions},
+    Consumer, DeserializeMessage, Error as PulsarError, Payload, Producer, Pulsar, PulsarBuilder,
     SerializeMessage, SubType, TokioExecutor,
 };
-use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::Mutex;
-#[derive(Debug, Deserialize, Serialize)]
-pub struct MessageData {
-    pub data: String,
-    pub partition_key: String,
-}
+use tokio::sync::RwLock;
 
 impl SerializeMessage for MessageData {
     fn serialize_message(input: Self) -> Result<producer::Message, PulsarError> {
@@ -21,6 +21,7 @@ impl SerializeMessage for MessageData {
         Ok(producer::Message {
             payload,
             partition_key: Some(input.partition_key),
+            deliver_at_time: input.deliver_at,
             ..Default::default()
         })
     }
@@ -34,92 +35,356 @@ impl DeserializeMessage for MessageData {
     }
 }
 
-pub struct AppState {
-    pub producer: Mutex<Producer<TokioExecutor>>,
-}
-
 pub struct PulsarClient {
     client: Pulsar<TokioExecutor>,
+    tenant: String,
+    namespace: String,
     topic_name: String,
 }
 
 impl PulsarClient {
-    #[tracing::instrument]
-    pub async fn new(url: String, topic_name: String) -> Result<Self, pulsar::Error> {
+    #[tracing::instrument(skip(builder))]
+    pub async fn new(
+        builder: PulsarBuilder<TokioExecutor>,
+        tenant: String,
+        namespace: String,
+        topic_name: String,
+    ) -> Result<Self, pulsar::Error> {
         tracing::info!("Establishing connection to the Pulsar server.");
-        let client = Pulsar::builder(url, TokioExecutor).build().await?;
-        Ok(Self { client, topic_name })
+        let client = builder.build().await?;
+        Ok(Self {
+            client,
+            tenant,
+            namespace,
+            topic_name,
+        })
+    }
+
+    fn get_topic(&self, name: &str) -> String {
+        format!("persistent://{}/{}/{}", self.tenant, self.namespace, name)
     }
 
     fn get_product_topic(&self) -> String {
-        format!("persistent://public/default/{}", &self.topic_name)
+        self.get_topic(&self.topic_name)
+    }
+
+    fn get_instance_topic(&self, instance_id: &str) -> String {
+        self.get_topic(&format!("{}-{}", self.topic_name, instance_id))
+    }
+
+    fn get_presence_topic(&self) -> String {
+        self.get_topic(&format!("{}-presence", self.topic_name))
     }
 
-    pub async fn get_producer(&self) -> Producer<TokioExecutor> {
+    async fn get_producer(
+        &self,
+        topic: String,
+        options: ProducerOptions,
+    ) -> Result<Producer<TokioExecutor>, PulsarError> {
         self.client
             .producer()
-            .with_topic(self.get_product_topic())
+            .with_topic(topic)
+            .with_options(options)
             .build()
             .await
-            .expect("Failed to create producer")
     }
+}
 
-    pub async fn get_consumer(
-        &self,
+type PulsarProducerPool = ProducerPool<Producer<TokioExecutor>>;
+
+/// `MessageBroker` on Pulsar: one topic shared by all instances (Key_Shared), one per instance,
+/// a compacted presence topic, plus the optional inbound and the dead letter topics.
+pub struct PulsarBroker {
+    client: PulsarClient,
+    /// Unique per instance
+    consumer_name: String,
+    subscription: String,
+    /// Of the shared topic's subscription
+    subscription_type: SubType,
+    inbound_topic: Option<String>,
+    dead_letter_topic: String,
+    /// lazily created, one pool of `pool_size` producers per topic and batching mode
+    producers: RwLock<HashMap<(String, bool), Arc<PulsarProducerPool>>>,
+    pool_size: usize,
+    /// Batching producers, every message is sent on its own when unset
+    batch_options: Option<ProducerOptions>,
+    /// Unacked messages are resent after it
+    unacked_resend_delay: Duration,
+}
+
+impl PulsarBroker {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        client: PulsarClient,
         consumer_name: String,
         subscription: String,
-    ) -> Consumer<MessageData, TokioExecutor> {
-        let consumer: Consumer<MessageData, TokioExecutor> = self
-            .client
-            .consumer()
-            .with_topic(self.topic_name.clone())
-            .with_consumer_name(consumer_name)
-            .with_subscription_type(SubType::KeyShared)
-            .with_subscription(subscription)
-            .with_unacked_message_resend_delay(Some(Duration::from_secs(10)))
-            .build()
-            .await
-            .expect("Failed to create consumer");
-        consumer
+        subscription_type: SubType,
+        inbound_topic: Option<String>,
+        dead_letter_topic: String,
+        pool_size: usize,
+        batch_options: Option<ProducerOptions>,
+    ) -> Self {
+        Self {
+            client,
+            consumer_name,
+            subscription,
+            subscription_type,
+            inbound_topic,
+            dead_letter_topic,
+            producers: RwLock::new(HashMap::new()),
+            pool_size: pool_size.max(1),
+            batch_options,
+            unacked_resend_delay: Duration::from_secs(30),
+        }
+    }
+
+    /// Should be `RedeliveryPolicy::resend_delay`, 30s by default.
+    pub fn with_unacked_resend_delay(mut self, unacked_resend_delay: Duration) -> Self {
+        self.unacked_resend_delay = unacked_resend_delay;
+        self
+    }
+
+    /// Topics whose consumers dispatch by key.
+    fn is_key_shared(&self, topic: &str) -> bool {
+        (self.subscription_type == SubType::KeyShared && topic == self.client.get_product_topic())
+            || self
+                .inbound_topic
+                .as_ref()
+                .is_some_and(|inbound| topic == self.client.get_topic(inbound))
     }
 
-    pub async fn start_consumer(
+    async fn get_pool(
         &self,
-        mut consumer: Consumer<MessageData, TokioExecutor>,
-        websocket_client: Data<Addr<Server>>,
-    ) {
-        tokio::spawn(async move {
-            while let Some(result) = consumer.try_next().await.transpose() {
-                match result {
-                    Ok(msg) => {
-                        let partition_key = msg.metadata().partition_key();
-                        if websocket_client
-                            .send(SessionExists {
-                                id: partition_key.to_owned(),
-                            })
-                            .await
-                            .unwrap_or(false)
-                        {
-                            if let Err(e) = consumer.ack(&msg).await {
-                                eprintln!("Failed to acknowledge message: {:?}", e);
-                            }
-                            let message_data: MessageData = msg.deserialize().unwrap();
-                            let websocket_data =
-                                serde_json::from_str::<MessageToClient>(&message_data.data)
-                                    .unwrap();
-                            websocket_client.do_send(websocket_data);
-                        } else {
-                            println!(
-                                "No active WebSocket session found for partition key: {}",
-                                partition_key
-                            );
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to receive message: {:?}", e);
-                    }
-                }
-            }
-        });
+        topic: &str,
+        batched: bool,
+    ) -> Result<Arc<PulsarProducerPool>, PulsarError> {
+        let pool_key = (topic.to_string(), batched);
+        if let Some(pool) = self.producers.read().await.get(&pool_key) {
+            return Ok(pool.clone());
+        }
+        let mut producers = self.producers.write().await;
+        if let Some(pool) = producers.get(&pool_key) {
+            return Ok(pool.clone());
+        }
+        let options = match &self.batch_options {
+            Some(options) if batched => options.clone(),
+            _ => ProducerOptions::default(),
+        };
+        let mut shards = Vec::with_capacity(self.pool_size);
+        for _ in 0..self.pool_size {
+            shards.push(
+                self.client
+                    .get_producer(topic.to_string(), options.clone())
+                    .await?,
+            );
+        }
+        let pool = Arc::new(ProducerPool::new(shards));
+        producers.insert(pool_key, pool.clone());
+        Ok(pool)
+    }
+
+    /// Only enqueueing holds the shard's lock, the broker receipt is awaited after releasing it.
+    async fn send(
+        &self,
+        topic: String,
+        partition_key: &str,
+        message: producer::Message,
+    ) -> Result<(), PulsarError> {
+        let batched =
+            self.batch_options.is_some() && can_batch(self.is_key_shared(&topic), &message);
+        let pool = self.get_pool(&topic, batched).await?;
+        let result = async {
+            // the guard is dropped with this block, before the receipt is awaited
+            let receipt = {
+                let mut producer = pool.get(partition_key).await;
+                producer.send_non_blocking(message).await?
+            };
+            receipt.await.map(|_| ())
+        }
+        .await;
+        if let Err(PulsarError::Connection(_) | PulsarError::Producer(_)) = &result {
+            // the next send recreates the topic's producers
+            self.producers.write().await.remove(&(topic, batched));
+        }
+        result
+    }
+}
+
+/// pulsar-rs drops `deliver_at_time` from batched messages, which would deliver deferred messages
+/// at once, and its batches mix keys, which Key_Shared consumers can't dispatch per key.
+fn can_batch(key_shared: bool, message: &producer::Message) -> bool {
+    !key_shared && message.deliver_at_time.is_none()
+}
+
+#[async_trait]
+impl MessageBroker for PulsarBroker {
+    async fn publish(
+        &self,
+        destination: Destination<'_>,
+        message: MessageData,
+    ) -> Result<(), anyhow::Error> {
+        let topic = match destination {
+            Destination::Shared => self.client.get_product_topic(),
+            Destination::Instance(instance_id) => self.client.get_instance_topic(instance_id),
+            Destination::Presence => self.client.get_presence_topic(),
+            Destination::Inbound => match &self.inbound_topic {
+                Some(topic) => self.client.get_topic(topic),
+                None => anyhow::bail!("Inbound topic is not configured"),
+            },
+        };
+        let partition_key = message.partition_key.clone();
+        self.send(
+            topic,
+            &partition_key,
+            MessageData::serialize_message(message)?,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn subscribe(
+        &self,
+        source: Source,
+    ) -> Result<Box<dyn BrokerSubscription>, anyhow::Error> {
+        let builder = self.client.client.consumer();
+        let builder = match source {
+            Source::Shared => builder
+                .with_topic(self.client.get_product_topic())
+                .with_consumer_name(self.consumer_name.clone())
+                .with_subscription_type(self.subscription_type)
+                .with_subscription(self.subscription.clone()),
+            // only this instance reads its own topic
+            Source::Instance(instance_id) => builder
+                .with_topic(self.client.get_instance_topic(&instance_id))
+                .with_consumer_name(format!("{}-direct", self.consumer_name))
+                .with_subscription_type(SubType::Exclusive)
+                .with_subscription(format!("{}-{}", self.subscription, instance_id)),
+            // replays the compacted presence topic on every start
+            Source::Presence(instance_id) => builder
+                .with_topic(self.client.get_presence_topic())
+                .with_consumer_name(format!("presence-{}", instance_id))
+                .with_subscription_type(SubType::Exclusive)
+                .with_subscription(format!("presence-{}", instance_id))
+                .with_options(ConsumerOptions {
+                    durable: Some(false),
+                    read_compacted: Some(true),
+                    initial_position: InitialPosition::Earliest,
+                    ..Default::default()
+                }),
+            Source::Inbound => match &self.inbound_topic {
+                Some(topic) => builder
+                    .with_topic(self.client.get_topic(topic))
+                    .with_consumer_name(format!("{}-inbound", self.consumer_name))
+                    .with_subscription_type(SubType::KeyShared)
+                    .with_subscription(format!("{}-inbound", self.subscription)),
+                None => anyhow::bail!("Inbound topic is not configured"),
+            },
+        };
+        let consumer: Consumer<MessageData, TokioExecutor> = builder
+            .with_unacked_message_resend_delay(Some(self.unacked_resend_delay))
+            .build()
+            .await?;
+        Ok(Box::new(PulsarSubscription {
+            consumer,
+            pending: HashMap::new(),
+            next_handle: 0,
+        }))
+    }
+
+    async fn dead_letter(&self, delivery: &Delivery, reason: &str) -> Result<(), anyhow::Error> {
+        let message = producer::Message {
+            payload: delivery.payload.clone(),
+            partition_key: Some(delivery.partition_key.clone()),
+            properties: HashMap::from([
+                ("reason".to_string(), reason.to_string()),
+                ("origin_message_id".to_string(), delivery.message_id.clone()),
+            ]),
+            ..Default::default()
+        };
+        self.send(
+            self.client.get_topic(&self.dead_letter_topic),
+            &delivery.partition_key,
+            message,
+        )
+        .await?;
+        Ok(())
+    }
+
+    fn accepts_inbound(&self) -> bool {
+        self.inbound_topic.is_some()
+    }
+}
+
+pub struct PulsarSubscription {
+    consumer: Consumer<MessageData, TokioExecutor>,
+    /// received messages by delivery handle, needed to ack or nack them
+    pending: HashMap<u64, consumer::Message<MessageData>>,
+    next_handle: u64,
+}
+
+#[async_trait]
+impl BrokerSubscription for PulsarSubscription {
+    async fn next(&mut self) -> Option<Result<Delivery, anyhow::Error>> {
+        let msg = match self.consumer.try_next().await {
+            Ok(Some(msg)) => msg,
+            Ok(None) => return None,
+            Err(e) => return Some(Err(e.into())),
+        };
+        self.next_handle += 1;
+        let id = msg.message_id();
+        let delivery = Delivery {
+            handle: self.next_handle,
+            message_id: format!("{}:{}:{:?}", id.ledger_id, id.entry_id, id.batch_index),
+            partition_key: msg.metadata().partition_key().to_owned(),
+            payload: msg.payload.data.clone(),
+        };
+        self.pending.insert(self.next_handle, msg);
+        Some(Ok(delivery))
+    }
+
+    async fn ack(&mut self, delivery: &Delivery) -> Result<(), anyhow::Error> {
+        if let Some(msg) = self.pending.remove(&delivery.handle) {
+            self.consumer.ack(&msg).await?;
+        }
+        Ok(())
+    }
+
+    async fn nack(&mut self, delivery: &Delivery) -> Result<(), anyhow::Error> {
+        if let Some(msg) = self.pending.remove(&delivery.handle) {
+            self.consumer.nack(&msg).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(deliver_at: Option<i64>) -> producer::Message {
+        MessageData::serialize_message(MessageData {
+            data: "{}".to_string(),
+            partition_key: "key".to_string(),
+            deliver_at,
+            control: false,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn immediate_messages_are_batched() {
+        assert!(can_batch(false, &message(None)));
+    }
+
+    #[test]
+    fn deferred_messages_keep_their_delivery_time_outside_batches() {
+        let message = message(Some(1_700_000_000_000));
+        assert_eq!(message.deliver_at_time, Some(1_700_000_000_000));
+        assert!(!can_batch(false, &message));
+    }
+
+    #[test]
+    fn key_shared_topics_are_not_batched() {
+        assert!(!can_batch(true, &message(None)));
     }
 }
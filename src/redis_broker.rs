@@ -0,0 +1,499 @@
+use crate::broker::{
+    BrokerSubscription, Delivery, Destination, MessageBroker, MessageData, Source,
+};
+use async_trait::async_trait;
+use redis::aio::{ConnectionManager, MultiplexedConnection};
+use redis::streams::{
+    StreamAutoClaimOptions, StreamAutoClaimReply, StreamId, StreamMaxlen, StreamReadOptions,
+    StreamReadReply,
+};
+use redis::{AsyncCommands, Client, RedisError, Script};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::{error, warn};
+
+const READ_COUNT: usize = 32;
+const BLOCK_MS: usize = 1000;
+/// How often due deferred messages are moved to their stream
+const DELAYED_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// `MessageBroker` on Redis Streams: each destination is a stream read through a consumer group,
+/// except the presence stream which every instance reads in full.
+/// Deferred messages wait in a sorted set scored by their delivery time.
+pub struct RedisBroker {
+    client: Client,
+    connection: ConnectionManager,
+    stream: String,
+    group: String,
+    /// Unique per instance, consumers of a group share nothing else
+    consumer_name: String,
+    inbound_stream: Option<String>,
+    dead_letter_stream: String,
+    max_len: usize,
+    /// Pending entries idle for longer are claimed again, from this or a crashed consumer
+    claim_idle: Duration,
+}
+
+impl RedisBroker {
+    #[tracing::instrument(skip(url))]
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
+        url: &str,
+        stream: String,
+        group: String,
+        consumer_name: String,
+        inbound_stream: Option<String>,
+        dead_letter_stream: String,
+        max_len: usize,
+        claim_idle: Duration,
+    ) -> Result<Self, RedisError> {
+        tracing::info!("Establishing connection to the Redis server.");
+        let client = Client::open(url)?;
+        let connection = client.get_connection_manager().await?;
+        let broker = Self {
+            client,
+            connection,
+            stream,
+            group,
+            consumer_name,
+            inbound_stream,
+            dead_letter_stream,
+            max_len,
+            claim_idle,
+        };
+        broker.start_delayed_mover();
+        Ok(broker)
+    }
+
+    fn destination_stream(&self, destination: Destination<'_>) -> Result<String, anyhow::Error> {
+        Ok(match destination {
+            Destination::Shared => self.stream.clone(),
+            Destination::Instance(instance_id) => format!("{}-{}", self.stream, instance_id),
+            Destination::Presence => format!("{}-presence", self.stream),
+            Destination::Inbound => match &self.inbound_stream {
+                Some(stream) => stream.clone(),
+                None => anyhow::bail!("Inbound stream is not configured"),
+            },
+        })
+    }
+
+    fn delayed_key(&self) -> String {
+        format!("{}-delayed", self.stream)
+    }
+
+    /// Trimmed to about `max_len` entries when set.
+    async fn add(
+        connection: &mut ConnectionManager,
+        stream: &str,
+        max_len: Option<usize>,
+        fields: &[(&str, &[u8])],
+    ) -> Result<String, RedisError> {
+        match max_len {
+            Some(max_len) => {
+                connection
+                    .xadd_maxlen(stream, StreamMaxlen::Approx(max_len), "*", fields)
+                    .await
+            }
+            None => connection.xadd(stream, "*", fields).await,
+        }
+    }
+
+    /// Presence is never trimmed, the online event of a long-lived connection must stay.
+    fn max_len_of(&self, destination: Destination<'_>) -> Option<usize> {
+        match destination {
+            Destination::Presence => None,
+            _ => Some(self.max_len),
+        }
+    }
+
+    /// Moves deferred messages whose time has come to their stream. The move runs as one script,
+    /// so each is added once however many instances poll, and stays in the set if the add fails.
+    fn start_delayed_mover(&self) {
+        let mut connection = self.connection.clone();
+        let delayed_key = self.delayed_key();
+        let max_len = self.max_len;
+        let move_due = Script::new(MOVE_DUE);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(DELAYED_POLL_INTERVAL).await;
+                let due: Vec<Vec<u8>> = match connection
+                    .zrangebyscore_limit(&delayed_key, "-inf", now_millis(), 0, READ_COUNT as isize)
+                    .await
+                {
+                    Ok(due) => due,
+                    Err(e) => {
+                        error!("Failed to read deferred messages: {:?}", e);
+                        continue;
+                    }
+                };
+                for entry in due {
+                    let Ok(delayed) = serde_json::from_slice::<DelayedMessage>(&entry) else {
+                        warn!("Invalid deferred message");
+                        let _: Result<usize, RedisError> =
+                            connection.zrem(&delayed_key, &entry).await;
+                        continue;
+                    };
+                    let result: Result<usize, RedisError> = move_due
+                        .key(&delayed_key)
+                        .key(&delayed.stream)
+                        .arg(&entry)
+                        .arg(max_len)
+                        .arg(&delayed.payload)
+                        .arg(&delayed.partition_key)
+                        .invoke_async(&mut connection)
+                        .await;
+                    if let Err(e) = result {
+                        error!("Failed to publish deferred message: {:?}", e);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Creates the consumer group reading new entries, an existing group is kept as is.
+    async fn ensure_group(&self, stream: &str) -> Result<(), RedisError> {
+        let mut connection = self.connection.clone();
+        let result: Result<(), RedisError> = connection
+            .xgroup_create_mkstream(stream, &self.group, "$")
+            .await;
+        match result {
+            Err(e) if e.code() != Some("BUSYGROUP") => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Adds a due entry to its stream and only then removes it from the deferred set, an entry
+/// another instance moved already is skipped. A failed `XADD` aborts before the `ZREM`.
+const MOVE_DUE: &str = r"
+if not redis.call('ZSCORE', KEYS[1], ARGV[1]) then
+    return 0
+end
+redis.call('XADD', KEYS[2], 'MAXLEN', '~', ARGV[2], '*',
+    'payload', ARGV[3], 'partition_key', ARGV[4])
+redis.call('ZREM', KEYS[1], ARGV[1])
+return 1
+";
+
+/// Entry of the deferred sorted set, unique per publish thanks to `nonce`.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct DelayedMessage {
+    stream: String,
+    payload: String,
+    partition_key: String,
+    nonce: uuid::Uuid,
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+#[async_trait]
+impl MessageBroker for RedisBroker {
+    async fn publish(
+        &self,
+        destination: Destination<'_>,
+        message: MessageData,
+    ) -> Result<(), anyhow::Error> {
+        let stream = self.destination_stream(destination)?;
+        let payload = serde_json::to_string(&message)?;
+        let mut connection = self.connection.clone();
+        match message.deliver_at {
+            Some(deliver_at) if deliver_at > now_millis() => {
+                let delayed = DelayedMessage {
+                    stream,
+                    payload,
+                    partition_key: message.partition_key,
+                    nonce: uuid::Uuid::new_v4(),
+                };
+                let _: () = connection
+                    .zadd(
+                        self.delayed_key(),
+                        serde_json::to_vec(&delayed)?,
+                        deliver_at,
+                    )
+                    .await?;
+            }
+            _ => {
+                Self::add(
+                    &mut connection,
+                    &stream,
+                    self.max_len_of(destination),
+                    &[
+                        ("payload", payload.as_bytes()),
+                        ("partition_key", message.partition_key.as_bytes()),
+                    ],
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn subscribe(
+        &self,
+        source: Source,
+    ) -> Result<Box<dyn BrokerSubscription>, anyhow::Error> {
+        let (stream, mode) = match &source {
+            Source::Presence(_) => (
+                self.destination_stream(Destination::Presence)?,
+                // every instance replays the whole presence history
+                ReadMode::Tail {
+                    last_id: "0".to_string(),
+                },
+            ),
+            Source::Shared => (
+                self.destination_stream(Destination::Shared)?,
+                ReadMode::Group {
+                    consumer: self.consumer_name.clone(),
+                },
+            ),
+            Source::Instance(instance_id) => (
+                self.destination_stream(Destination::Instance(instance_id))?,
+                ReadMode::Group {
+                    consumer: self.consumer_name.clone(),
+                },
+            ),
+            Source::Inbound => (
+                self.destination_stream(Destination::Inbound)?,
+                ReadMode::Group {
+                    consumer: format!("{}-inbound", self.consumer_name),
+                },
+            ),
+        };
+        if let ReadMode::Group { .. } = mode {
+            self.ensure_group(&stream).await?;
+        }
+        // blocking reads get their own connection so they don't hold up other commands
+        let reader_connection = self.client.get_multiplexed_async_connection().await?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let in_flight = InFlight::default();
+        let reader = StreamReader {
+            connection: reader_connection,
+            stream: stream.clone(),
+            group: self.group.clone(),
+            mode,
+            claim_idle: self.claim_idle,
+            claim_cursor: "0-0".to_string(),
+            in_flight: in_flight.clone(),
+            sender: sender.clone(),
+        };
+        tokio::spawn(reader.run());
+        Ok(Box::new(RedisSubscription {
+            connection: self.connection.clone(),
+            stream,
+            group: self.group.clone(),
+            acks: !matches!(source, Source::Presence(_)),
+            sender,
+            receiver,
+            pending: HashMap::new(),
+            in_flight,
+            next_handle: 0,
+        }))
+    }
+
+    async fn dead_letter(&self, delivery: &Delivery, reason: &str) -> Result<(), anyhow::Error> {
+        let mut connection = self.connection.clone();
+        Self::add(
+            &mut connection,
+            &self.dead_letter_stream,
+            Some(self.max_len),
+            &[
+                ("payload", delivery.payload.as_slice()),
+                ("partition_key", delivery.partition_key.as_bytes()),
+                ("reason", reason.as_bytes()),
+                ("origin_message_id", delivery.message_id.as_bytes()),
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    fn accepts_inbound(&self) -> bool {
+        self.inbound_stream.is_some()
+    }
+}
+
+enum ReadMode {
+    /// `XREADGROUP` as `consumer`, entries are acked and reclaimed through the group
+    Group { consumer: String },
+    /// Plain `XREAD` from `last_id`, nothing to ack
+    Tail { last_id: String },
+}
+
+/// An entry read from the stream, not yet handed out by the subscription.
+struct Entry {
+    id: String,
+    partition_key: String,
+    payload: Vec<u8>,
+}
+
+impl Entry {
+    fn from_stream_id(stream_id: StreamId) -> Self {
+        Self {
+            partition_key: stream_id.get("partition_key").unwrap_or_default(),
+            payload: stream_id.get("payload").unwrap_or_default(),
+            id: stream_id.id,
+        }
+    }
+}
+
+/// Ids of the entries handed to the subscription and not acked yet, including those waiting to
+/// be retried, which must not be claimed again.
+type InFlight = Arc<Mutex<HashSet<String>>>;
+
+/// Reads the stream on a task of its own so the subscription's `next` stays cancel-safe.
+struct StreamReader {
+    connection: MultiplexedConnection,
+    stream: String,
+    group: String,
+    mode: ReadMode,
+    claim_idle: Duration,
+    /// Where the next `XAUTOCLAIM` continues the scan of the pending entries
+    claim_cursor: String,
+    in_flight: InFlight,
+    sender: mpsc::UnboundedSender<Result<Entry, anyhow::Error>>,
+}
+
+impl StreamReader {
+    async fn run(mut self) {
+        let mut last_claim = Instant::now();
+        while !self.sender.is_closed() {
+            let result = if last_claim.elapsed() >= self.claim_idle {
+                last_claim = Instant::now();
+                self.claim().await
+            } else {
+                self.read().await
+            };
+            match result {
+                Ok(entries) => {
+                    for entry in entries {
+                        if !self.in_flight.lock().unwrap().insert(entry.id.clone()) {
+                            continue;
+                        }
+                        let _ = self.sender.send(Ok(entry));
+                    }
+                }
+                Err(e) => {
+                    let _ = self.sender.send(Err(e.into()));
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+
+    async fn read(&mut self) -> Result<Vec<Entry>, RedisError> {
+        let options = StreamReadOptions::default()
+            .count(READ_COUNT)
+            .block(BLOCK_MS);
+        let reply: Option<StreamReadReply> = match &mut self.mode {
+            ReadMode::Group { consumer } => {
+                let options = options.group(&self.group, consumer.as_str());
+                self.connection
+                    .xread_options(&[&self.stream], &[">"], &options)
+                    .await?
+            }
+            ReadMode::Tail { last_id } => {
+                self.connection
+                    .xread_options(&[&self.stream], &[last_id.as_str()], &options)
+                    .await?
+            }
+        };
+        let entries: Vec<Entry> = reply
+            .into_iter()
+            .flat_map(|reply| reply.keys)
+            .flat_map(|key| key.ids)
+            .map(Entry::from_stream_id)
+            .collect();
+        if let (ReadMode::Tail { last_id }, Some(entry)) = (&mut self.mode, entries.last()) {
+            *last_id = entry.id.clone();
+        }
+        Ok(entries)
+    }
+
+    /// Takes over entries left unacked for longer than `claim_idle`, a batch per call. Entries
+    /// this subscription still holds are skipped when handed out.
+    async fn claim(&mut self) -> Result<Vec<Entry>, RedisError> {
+        let ReadMode::Group { consumer } = &self.mode else {
+            return Ok(Vec::new());
+        };
+        let reply: StreamAutoClaimReply = self
+            .connection
+            .xautoclaim_options(
+                &self.stream,
+                &self.group,
+                consumer,
+                self.claim_idle.as_millis() as u64,
+                self.claim_cursor.as_str(),
+                StreamAutoClaimOptions::default().count(READ_COUNT),
+            )
+            .await?;
+        self.claim_cursor = reply.next_stream_id;
+        Ok(reply
+            .claimed
+            .into_iter()
+            .map(Entry::from_stream_id)
+            .collect())
+    }
+}
+
+pub struct RedisSubscription {
+    connection: ConnectionManager,
+    stream: String,
+    group: String,
+    /// false for the presence stream, read outside any group
+    acks: bool,
+    sender: mpsc::UnboundedSender<Result<Entry, anyhow::Error>>,
+    receiver: mpsc::UnboundedReceiver<Result<Entry, anyhow::Error>>,
+    pending: HashMap<u64, Entry>,
+    in_flight: InFlight,
+    next_handle: u64,
+}
+
+#[async_trait]
+impl BrokerSubscription for RedisSubscription {
+    async fn next(&mut self) -> Option<Result<Delivery, anyhow::Error>> {
+        let entry = match self.receiver.recv().await? {
+            Ok(entry) => entry,
+            Err(e) => return Some(Err(e)),
+        };
+        self.next_handle += 1;
+        let delivery = Delivery {
+            handle: self.next_handle,
+            message_id: entry.id.clone(),
+            partition_key: entry.partition_key.clone(),
+            payload: entry.payload.clone(),
+        };
+        self.pending.insert(self.next_handle, entry);
+        Some(Ok(delivery))
+    }
+
+    async fn ack(&mut self, delivery: &Delivery) -> Result<(), anyhow::Error> {
+        if let Some(entry) = self.pending.remove(&delivery.handle) {
+            // claimable again should the ack fail
+            self.in_flight.lock().unwrap().remove(&entry.id);
+            if self.acks {
+                let _: usize = self
+                    .connection
+                    .xack(&self.stream, &self.group, &[entry.id])
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Streams can't be told to redeliver, the entry stays pending and is handed out again.
+    async fn nack(&mut self, delivery: &Delivery) -> Result<(), anyhow::Error> {
+        if let Some(entry) = self.pending.remove(&delivery.handle) {
+            self.sender
+                .send(Ok(entry))
+                .map_err(|_| anyhow::anyhow!("Subscription is closed"))?;
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,8 @@
 
-use crate::handlers::{health_check, send_web_socket, web_socket};
+use crate::handlers::{
+    count_sessions, events, get_session, health_check, kick_sessions, list_sessions,
+    long_poll, send_web_socket, send_web_socket_batch, web_socket,
+};
 use crate::middlewares::RequireAuth;
 use crate::openapi::ApiDoc;
 use actix_web::web;
@@ -11,6 +14,13 @@ pub fn routes(cfg: &mut web::ServiceConfig) {
     cfg
         .route("/", web::get().to(health_check))
         .route("/websocket", web::get().to(web_socket))
+        .route("/events", web::get().to(events))
+        .route("/poll", web::get().to(long_poll))
         .route("/send", web::post().to(send_web_socket).wrap(RequireAuth))
+        .route("/send/batch", web::post().to(send_web_socket_batch).wrap(RequireAuth))
+        .route("/admin/sessions", web::get().to(list_sessions).wrap(RequireAuth))
+        .route("/admin/sessions/count", web::get().to(count_sessions).wrap(RequireAuth))
+        .route("/admin/sessions/kick", web::post().to(kick_sessions).wrap(RequireAuth))
+        .route("/admin/sessions/{conn_id}", web::get().to(get_session).wrap(RequireAuth))
         .service(SwaggerUi::new("/docs/{_:.*}").url("/api-docs/openapi.json", openapi.clone()));
 }
@@ -1,17 +1,52 @@
-use crate::{errors::GenericError, pulsar_client::PulsarClient, websocket::WebSocketActionType};
+use crate::{
+    broker::{Backoff, MessageBroker, RedeliveryPolicy},
+    buffer::{MemoryBuffer, MessageBuffer, SledBuffer},
+    errors::GenericError,
+    kafka_broker::KafkaBroker,
+    long_poll::PollBatch,
+    memory_broker::MemoryBroker,
+    ondc::validate_payload,
+    pulsar_client::{PulsarBroker, PulsarClient},
+    redis_broker::RedisBroker,
+    websocket::{KickSessions, MessageTarget, MessageToClient, WebSocketActionType},
+};
 use actix_http::Payload;
 use actix_web::{web, FromRequest, HttpRequest};
+use actix_web_actors::ws::{CloseCode, CloseReason};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
 use futures::future::LocalBoxFuture;
-use secrecy::SecretString;
+use pulsar::{
+    authentication::oauth2::{OAuth2Authentication, OAuth2Params},
+    producer::ProducerOptions,
+    Authentication, Pulsar, PulsarBuilder, SubType, TokioExecutor,
+};
+use secrecy::{ExposeSecret, SecretString};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use utoipa::ToSchema;
 use uuid::Uuid;
 
-#[derive(Debug, Deserialize, Serialize)]
+pub const SEND_SCOPE: &str = "send";
+pub const BROADCAST_SCOPE: &str = "broadcast";
+pub const ADMIN_SCOPE: &str = "admin";
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct JWTClaims {
     pub sub: String,
     pub exp: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub business_id: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<String>,
+}
+
+impl JWTClaims {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
 }
 
 #[derive(Serialize, Debug, ToSchema)]
@@ -39,11 +74,41 @@ impl GenericResponse {
     }
 }
 
+#[derive(Serialize, Debug, ToSchema)]
+pub struct DataResponse<T> {
+    pub status: bool,
+    pub customer_message: String,
+    pub code: String,
+    pub data: T,
+}
+
+impl<T> DataResponse<T> {
+    pub fn success(message: &str, data: T) -> Self {
+        Self {
+            status: true,
+            customer_message: String::from(message),
+            code: String::from("200"),
+            data,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct ApplicationSetting {
     pub port: u16,
     pub host: String,
     pub workers: usize,
+    /// Unique per replica, defaults to the hostname.
+    pub instance_id: Option<String>,
+    /// Text frames above this size are sent in chunks.
+    pub max_frame_bytes: Option<usize>,
+}
+
+impl ApplicationSetting {
+    pub fn max_frame_bytes(&self) -> usize {
+        // a chunk has to hold at least a few characters
+        self.max_frame_bytes.unwrap_or(64 * 1024).max(1024)
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -57,17 +122,435 @@ pub struct SecretSetting {
     pub jwt: Jwt,
 }
 
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PulsarSubscriptionType {
+    Exclusive,
+    Shared,
+    Failover,
+    #[default]
+    KeyShared,
+}
+
+impl PulsarSubscriptionType {
+    pub fn sub_type(&self) -> SubType {
+        match self {
+            PulsarSubscriptionType::Exclusive => SubType::Exclusive,
+            PulsarSubscriptionType::Shared => SubType::Shared,
+            PulsarSubscriptionType::Failover => SubType::Failover,
+            PulsarSubscriptionType::KeyShared => SubType::KeyShared,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PulsarAuthSetting {
+    Token {
+        token: SecretString,
+    },
+    /// Client credentials flow, `credentials_url` is a `file://` or `data:` URL of the
+    /// JSON holding `client_id` and `client_secret`
+    Oauth2 {
+        issuer_url: String,
+        credentials_url: SecretString,
+        audience: Option<String>,
+        scope: Option<String>,
+    },
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PulsarTlsSetting {
+    /// PEM file of the CA certificates trusted for `pulsar+ssl://` urls
+    pub ca_cert_path: Option<String>,
+    pub hostname_verification: bool,
+    /// Accepts any certificate, for development only
+    pub allow_insecure: bool,
+}
+
+impl Default for PulsarTlsSetting {
+    fn default() -> Self {
+        Self {
+            ca_cert_path: None,
+            hostname_verification: true,
+            allow_insecure: false,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct PulsarSetting {
+    /// Defaults to `public`
+    pub tenant: Option<String>,
+    /// Defaults to `default`
+    pub namespace: Option<String>,
     pub topic: String,
+    /// Prefix of the consumer names, suffixed with the instance id
     pub consumer: String,
     pub subscription: String,
+    /// Of the shared topic's subscription, defaults to `key_shared`
+    #[serde(default)]
+    pub subscription_type: PulsarSubscriptionType,
     pub url: String,
+    pub auth: Option<PulsarAuthSetting>,
+    #[serde(default)]
+    pub tls: PulsarTlsSetting,
+    /// Topic for actions sent by clients over the socket, inbound frames are rejected when unset.
+    pub inbound_topic: Option<String>,
+    /// Defaults to `{topic}-dlq`
+    pub dead_letter_topic: Option<String>,
+    /// Producers per topic, defaults to 4
+    pub producer_pool_size: Option<usize>,
+    /// Messages per batch, batching is off when unset. Deferred messages and topics read with
+    /// Key_Shared are never batched.
+    pub batch_size: Option<u32>,
+    /// Longest a message waits for its batch to fill, defaults to 10ms
+    pub batch_timeout_ms: Option<u64>,
 }
 
 impl PulsarSetting {
-    pub async fn client(self) -> Result<PulsarClient, pulsar::Error> {
-        PulsarClient::new(self.url, self.topic).await
+    /// Options of the batching producers, `None` when batching is off.
+    pub fn batch_options(&self) -> Option<ProducerOptions> {
+        let batch_size = self.batch_size.filter(|size| *size > 1)?;
+        Some(ProducerOptions {
+            batch_size: Some(batch_size),
+            batch_timeout: Some(Duration::from_millis(self.batch_timeout_ms.unwrap_or(10))),
+            ..Default::default()
+        })
+    }
+
+    pub fn dead_letter_topic(&self) -> String {
+        self.dead_letter_topic
+            .clone()
+            .unwrap_or_else(|| format!("{}-dlq", self.topic))
+    }
+
+    fn builder(&self) -> Result<PulsarBuilder<TokioExecutor>, anyhow::Error> {
+        let mut builder = Pulsar::builder(self.url.clone(), TokioExecutor)
+            .with_tls_hostname_verification_enabled(self.tls.hostname_verification)
+            .with_allow_insecure_connection(self.tls.allow_insecure);
+        if let Some(path) = &self.tls.ca_cert_path {
+            builder = builder
+                .with_certificate_chain_file(path)
+                .context("Failed to read the Pulsar CA certificate")?;
+        }
+        builder = match &self.auth {
+            Some(PulsarAuthSetting::Token { token }) => builder.with_auth(Authentication {
+                name: "token".to_string(),
+                data: token.expose_secret().as_bytes().to_vec(),
+            }),
+            Some(PulsarAuthSetting::Oauth2 {
+                issuer_url,
+                credentials_url,
+                audience,
+                scope,
+            }) => {
+                builder.with_auth_provider(OAuth2Authentication::client_credentials(OAuth2Params {
+                    issuer_url: issuer_url.clone(),
+                    credentials_url: credentials_url.expose_secret().to_string(),
+                    audience: audience.clone(),
+                    scope: scope.clone(),
+                }))
+            }
+            None => builder,
+        };
+        Ok(builder)
+    }
+
+    pub async fn client(&self) -> Result<PulsarClient, anyhow::Error> {
+        Ok(PulsarClient::new(
+            self.builder()?,
+            self.tenant.clone().unwrap_or_else(|| "public".to_string()),
+            self.namespace
+                .clone()
+                .unwrap_or_else(|| "default".to_string()),
+            self.topic.clone(),
+        )
+        .await?)
+    }
+
+    pub async fn broker(&self, instance_id: &str) -> Result<PulsarBroker, anyhow::Error> {
+        Ok(PulsarBroker::new(
+            self.client().await?,
+            format!("{}-{}", self.consumer, instance_id),
+            self.subscription.clone(),
+            self.subscription_type.sub_type(),
+            self.inbound_topic.clone(),
+            self.dead_letter_topic(),
+            self.producer_pool_size.unwrap_or(4),
+            self.batch_options(),
+        ))
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RedisSetting {
+    pub url: String,
+    pub stream: String,
+    pub group: String,
+    /// Prefix of the consumer names, suffixed with the instance id
+    pub consumer: String,
+    /// Stream for actions sent by clients over the socket, inbound frames are rejected when unset.
+    pub inbound_stream: Option<String>,
+    /// Defaults to `{stream}-dlq`
+    pub dead_letter_stream: Option<String>,
+    /// Approximate cap on entries kept per stream, defaults to 100000
+    pub max_len: Option<usize>,
+    /// Unacked entries idle for longer are redelivered, defaults to the resend delay of the
+    /// redelivery policy
+    pub claim_idle_ms: Option<u64>,
+}
+
+impl RedisSetting {
+    pub async fn broker(
+        &self,
+        instance_id: &str,
+        resend_delay: Duration,
+    ) -> Result<RedisBroker, redis::RedisError> {
+        RedisBroker::new(
+            &self.url,
+            self.stream.clone(),
+            self.group.clone(),
+            format!("{}-{}", self.consumer, instance_id),
+            self.inbound_stream.clone(),
+            self.dead_letter_stream
+                .clone()
+                .unwrap_or_else(|| format!("{}-dlq", self.stream)),
+            self.max_len.unwrap_or(100_000),
+            self.claim_idle_ms
+                .map_or(resend_delay, Duration::from_millis),
+        )
+        .await
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct KafkaSetting {
+    /// Comma separated `host:port` list
+    pub brokers: String,
+    pub topic: String,
+    pub group: String,
+    /// Prefix of the client ids, suffixed with the instance id
+    pub consumer: String,
+    /// Topic for actions sent by clients over the socket, inbound frames are rejected when unset.
+    pub inbound_topic: Option<String>,
+    /// Defaults to `{topic}-dlq`
+    pub dead_letter_topic: Option<String>,
+}
+
+impl KafkaSetting {
+    pub fn broker(&self, instance_id: &str) -> Result<KafkaBroker, rdkafka::error::KafkaError> {
+        KafkaBroker::new(
+            self.brokers.clone(),
+            self.topic.clone(),
+            self.group.clone(),
+            format!("{}-{}", self.consumer, instance_id),
+            self.inbound_topic.clone(),
+            self.dead_letter_topic
+                .clone()
+                .unwrap_or_else(|| format!("{}-dlq", self.topic)),
+        )
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BrokerBackend {
+    #[default]
+    Pulsar,
+    Redis,
+    Kafka,
+    /// In-process channels, for local development and tests
+    Memory,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct BrokerSetting {
+    pub backend: BrokerBackend,
+    pub max_redelivery: u32,
+    pub nack_backoff_ms: u64,
+    /// How long startup keeps retrying to reach the broker before giving up
+    pub connect_timeout_secs: u64,
+}
+
+impl Default for BrokerSetting {
+    fn default() -> Self {
+        Self {
+            backend: BrokerBackend::Pulsar,
+            max_redelivery: 5,
+            nack_backoff_ms: 1000,
+            connect_timeout_secs: 60,
+        }
+    }
+}
+
+impl BrokerSetting {
+    pub fn redelivery_policy(&self) -> RedeliveryPolicy {
+        RedeliveryPolicy {
+            max_redelivery: self.max_redelivery,
+            backoff: Duration::from_millis(self.nack_backoff_ms),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+
+    /// Connects to the configured backend, retrying with backoff for `connect_timeout_secs`.
+    pub async fn build(
+        &self,
+        instance_id: &str,
+        pulsar: Option<&PulsarSetting>,
+        redis: Option<&RedisSetting>,
+        kafka: Option<&KafkaSetting>,
+    ) -> Result<Arc<dyn MessageBroker>, anyhow::Error> {
+        let deadline = Instant::now() + Duration::from_secs(self.connect_timeout_secs);
+        let mut backoff = Backoff::default();
+        loop {
+            match self.connect(instance_id, pulsar, redis, kafka).await {
+                Ok(broker) => return Ok(broker),
+                Err(e) if Instant::now() < deadline => {
+                    let delay = backoff.next_delay();
+                    tracing::warn!(
+                        "Failed to connect to the broker, retrying in {:?}: {:?}",
+                        delay,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => return Err(e.context("Failed to connect to the broker")),
+            }
+        }
+    }
+
+    async fn connect(
+        &self,
+        instance_id: &str,
+        pulsar: Option<&PulsarSetting>,
+        redis: Option<&RedisSetting>,
+        kafka: Option<&KafkaSetting>,
+    ) -> Result<Arc<dyn MessageBroker>, anyhow::Error> {
+        let resend_delay = self.redelivery_policy().resend_delay();
+        Ok(match self.backend {
+            BrokerBackend::Memory => Arc::new(MemoryBroker::default()),
+            BrokerBackend::Pulsar => {
+                let pulsar =
+                    pulsar.context("Pulsar settings are required for the pulsar broker")?;
+                Arc::new(
+                    pulsar
+                        .broker(instance_id)
+                        .await?
+                        .with_unacked_resend_delay(resend_delay),
+                )
+            }
+            BrokerBackend::Redis => {
+                let redis = redis.context("Redis settings are required for the redis broker")?;
+                Arc::new(redis.broker(instance_id, resend_delay).await?)
+            }
+            BrokerBackend::Kafka => {
+                let kafka = kafka.context("Kafka settings are required for the kafka broker")?;
+                let broker = kafka.broker(instance_id)?;
+                broker.start_delay_mover()?;
+                Arc::new(broker)
+            }
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BufferBackend {
+    #[default]
+    Memory,
+    Sled,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct BufferSetting {
+    pub backend: BufferBackend,
+    pub max_messages: usize,
+    /// Most messages the memory backend holds over all keys, the oldest are dropped beyond it
+    pub max_total_messages: usize,
+    pub ttl_secs: u64,
+    pub path: String,
+}
+
+impl Default for BufferSetting {
+    fn default() -> Self {
+        Self {
+            backend: BufferBackend::Memory,
+            max_messages: 100,
+            max_total_messages: 100_000,
+            ttl_secs: 3600,
+            path: "offline_messages".to_string(),
+        }
+    }
+}
+
+impl BufferSetting {
+    pub fn build(&self) -> Result<Box<dyn MessageBuffer>, anyhow::Error> {
+        anyhow::ensure!(
+            self.max_messages > 0,
+            "BUFFER__MAX_MESSAGES must be at least 1"
+        );
+        anyhow::ensure!(
+            self.max_total_messages > 0,
+            "BUFFER__MAX_TOTAL_MESSAGES must be at least 1"
+        );
+        Ok(match self.backend {
+            BufferBackend::Memory => Box::new(MemoryBuffer::new(
+                self.max_messages,
+                self.max_total_messages,
+            )),
+            BufferBackend::Sled => Box::new(SledBuffer::open(&self.path, self.max_messages)?),
+        })
+    }
+}
+
+/// What happens to a `/send` payload that doesn't match the ONDC callback of its action type.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidationMode {
+    /// Rejected with the field errors
+    Strict,
+    /// Sent anyway, the field errors are logged
+    #[default]
+    Warn,
+    Off,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ValidationSetting {
+    pub mode: ValidationMode,
+}
+
+impl ValidationSetting {
+    pub fn validate(
+        &self,
+        action_type: WebSocketActionType,
+        data: &Value,
+    ) -> Result<(), GenericError> {
+        if self.mode == ValidationMode::Off {
+            return Ok(());
+        }
+        let errors = validate_payload(action_type, data);
+        if errors.is_empty() {
+            return Ok(());
+        }
+        let errors = errors
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("; ");
+        if self.mode == ValidationMode::Warn {
+            tracing::warn!("Invalid {:?} payload: {}", action_type, errors);
+            return Ok(());
+        }
+        Err(GenericError::ValidationError(format!(
+            "Invalid {:?} payload: {}",
+            action_type, errors
+        )))
     }
 }
 
@@ -75,7 +558,15 @@ impl PulsarSetting {
 pub struct Settings {
     pub application: ApplicationSetting,
     pub secret: SecretSetting,
-    pub pulsar: PulsarSetting,
+    pub pulsar: Option<PulsarSetting>,
+    pub redis: Option<RedisSetting>,
+    pub kafka: Option<KafkaSetting>,
+    #[serde(default)]
+    pub broker: BrokerSetting,
+    #[serde(default)]
+    pub buffer: BufferSetting,
+    #[serde(default)]
+    pub validation: ValidationSetting,
 }
 
 #[derive(Debug, Deserialize)]
@@ -84,6 +575,105 @@ pub struct WebSocketParam {
     pub user_id: Option<Uuid>,
     pub business_id: Option<Uuid>,
     pub device_id: Option<String>,
+    pub token: Option<String>,
+    /// Last sequence the client received, buffered messages up to it are skipped.
+    pub last_seen: Option<u64>,
+}
+
+impl WebSocketParam {
+    /// Ensures the token was issued for the user and business the socket subscribes to. A key
+    /// with neither needs a token whose subject is the device or that has the admin scope.
+    pub fn validate_claims(&self, claims: &JWTClaims) -> Result<(), GenericError> {
+        if self.user_id.is_none() && self.business_id.is_none() {
+            let is_device = self.device_id.as_ref().is_some_and(|id| claims.sub == *id);
+            if !is_device && !claims.has_scope(ADMIN_SCOPE) {
+                return Err(GenericError::InvalidJWT(
+                    "Token is not valid for the requested device".to_string(),
+                ));
+            }
+        }
+        if let Some(user_id) = self.user_id {
+            if claims.sub != user_id.to_string() {
+                return Err(GenericError::InvalidJWT(
+                    "Token is not valid for the requested user".to_string(),
+                ));
+            }
+        }
+        if let Some(business_id) = self.business_id {
+            if claims.business_id != Some(business_id) {
+                return Err(GenericError::InvalidJWT(
+                    "Token is not valid for the requested business".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Longest a poll request is held open.
+const MAX_POLL_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_POLL_TIMEOUT_SECS: u64 = 25;
+
+#[derive(Debug, Deserialize)]
+pub struct PollParam {
+    /// Poll session of the client, returned by the previous poll.
+    pub session: Option<Uuid>,
+    /// Sequence of the last message received, messages up to it are acked and not returned again.
+    pub cursor: Option<u64>,
+    pub timeout_secs: Option<u64>,
+}
+
+impl PollParam {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.timeout_secs
+                .unwrap_or(DEFAULT_POLL_TIMEOUT_SECS)
+                .min(MAX_POLL_TIMEOUT_SECS),
+        )
+    }
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct PollClosed {
+    pub code: u16,
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct PollResult {
+    /// Poll session to send with the next poll, messages of a key are queued per session
+    #[schema(value_type = String)]
+    pub session: Uuid,
+    /// `MessageToClient` items in sequence order
+    pub messages: Vec<Value>,
+    /// Cursor to send with the next poll
+    pub cursor: Option<u64>,
+    /// Set when the session was closed through the kick API
+    pub closed: Option<PollClosed>,
+}
+
+impl PollResult {
+    pub fn new(batch: PollBatch, cursor: Option<u64>, session: Uuid) -> Self {
+        Self {
+            session,
+            messages: batch
+                .messages
+                .iter()
+                .filter_map(|message| serde_json::from_str(message).ok())
+                .collect(),
+            cursor: batch.cursor.or(cursor),
+            closed: batch.closed.map(|reason| PollClosed {
+                code: reason.code.into(),
+                reason: reason.description,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SessionFilter {
+    pub business_id: Option<Uuid>,
+    pub user_id: Option<Uuid>,
 }
 
 pub trait WSKeyTrait {
@@ -108,6 +698,56 @@ pub enum ProcessType {
     Deferred,
 }
 
+/// Which sessions a `/send` request reaches.
+#[derive(Deserialize, Debug, PartialEq, ToSchema, Default)]
+pub enum TargetType {
+    /// The exact `user_id#business_id#device_id` key
+    #[default]
+    Exact,
+    /// Every device of the business
+    Business,
+    /// Every device of the user, across businesses
+    User,
+    /// Every device of the user within the business
+    UserBusiness,
+    /// Every session of `business_id`, which is required, subscribed to the
+    /// `context.transaction_id` of `data`
+    Transaction,
+    /// Every connected session
+    Broadcast,
+}
+
+/// Group target of a request, `None` for `Exact` and `Broadcast`.
+fn resolve_target(
+    target: &TargetType,
+    user_id: Option<Uuid>,
+    business_id: Option<Uuid>,
+    transaction_id: Option<&str>,
+) -> Result<Option<MessageTarget>, GenericError> {
+    let missing = |field: &str| {
+        GenericError::ValidationError(format!("{} is required for {:?} target", field, target))
+    };
+    Ok(match target {
+        TargetType::Exact | TargetType::Broadcast => None,
+        TargetType::Business => Some(MessageTarget::Business {
+            business_id: business_id.ok_or_else(|| missing("business_id"))?,
+        }),
+        TargetType::User => Some(MessageTarget::User {
+            user_id: user_id.ok_or_else(|| missing("user_id"))?,
+        }),
+        TargetType::UserBusiness => Some(MessageTarget::UserBusiness {
+            user_id: user_id.ok_or_else(|| missing("user_id"))?,
+            business_id: business_id.ok_or_else(|| missing("business_id"))?,
+        }),
+        TargetType::Transaction => Some(MessageTarget::Transaction {
+            transaction_id: transaction_id
+                .ok_or_else(|| missing("data.context.transaction_id"))?
+                .to_string(),
+            business_id: business_id.ok_or_else(|| missing("business_id"))?,
+        }),
+    })
+}
+
 #[derive(Deserialize, Debug, ToSchema)]
 pub struct WSRequest {
     #[schema(value_type = String)]
@@ -118,6 +758,59 @@ pub struct WSRequest {
     pub action_type: WebSocketActionType,
     pub data: Value,
     pub process_type: Option<ProcessType>,
+    /// Delivery time for `Deferred` messages, mutually exclusive with `delay_ms`.
+    pub deliver_at: Option<DateTime<Utc>>,
+    /// Delay in milliseconds for `Deferred` messages, mutually exclusive with `deliver_at`.
+    pub delay_ms: Option<u64>,
+    #[serde(default)]
+    pub target: TargetType,
+}
+
+impl WSRequest {
+    pub fn to_message(&self) -> Result<MessageToClient, GenericError> {
+        let id = (self.target == TargetType::Exact).then(|| self.get_ws_key());
+        let message = MessageToClient::new(self.action_type, self.data.clone(), id);
+        let transaction_id = message.transaction_id.clone();
+        Ok(
+            match resolve_target(
+                &self.target,
+                self.user_id,
+                self.business_id,
+                transaction_id.as_deref(),
+            )? {
+                Some(target) => message.with_target(target),
+                None => message,
+            },
+        )
+    }
+
+    /// Returns the delivery time (epoch millis) of `Deferred` requests, held back by the broker
+    /// until then.
+    pub fn deliver_at_millis(&self) -> Result<Option<i64>, GenericError> {
+        if self.process_type != Some(ProcessType::Deferred) {
+            if self.deliver_at.is_some() || self.delay_ms.is_some() {
+                return Err(GenericError::ValidationError(
+                    "deliver_at / delay_ms are only allowed with Deferred process_type".to_string(),
+                ));
+            }
+            return Ok(None);
+        }
+        match (self.deliver_at, self.delay_ms) {
+            (Some(deliver_at), None) => Ok(Some(deliver_at.timestamp_millis())),
+            (None, Some(delay_ms)) => {
+                let delay = i64::try_from(delay_ms).map_err(|_| {
+                    GenericError::ValidationError("delay_ms is too large".to_string())
+                })?;
+                Ok(Some(Utc::now().timestamp_millis().saturating_add(delay)))
+            }
+            (Some(_), Some(_)) => Err(GenericError::ValidationError(
+                "Only one of deliver_at or delay_ms can be set".to_string(),
+            )),
+            (None, None) => Err(GenericError::ValidationError(
+                "Deferred process_type requires deliver_at or delay_ms".to_string(),
+            )),
+        }
+    }
 }
 
 impl WSKeyTrait for WSRequest {
@@ -147,3 +840,192 @@ impl FromRequest for WSRequest {
         })
     }
 }
+
+/// Close code sent when a kick request has none: policy violation.
+const DEFAULT_KICK_CODE: u16 = 1008;
+
+/// Body of `/admin/sessions/kick`, sessions are selected like the `/send` targets.
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct KickRequest {
+    #[schema(value_type = String)]
+    pub user_id: Option<Uuid>,
+    #[schema(value_type = String)]
+    pub business_id: Option<Uuid>,
+    pub device_id: Option<String>,
+    /// Any target but `Broadcast` and `Transaction`
+    #[serde(default)]
+    pub target: TargetType,
+    /// WebSocket close code, 1000-1003, 1007-1014 or 3000-4999, defaults to 1008
+    pub code: Option<u16>,
+    /// Close reason shown to the client, at most 123 bytes
+    pub reason: Option<String>,
+}
+
+impl KickRequest {
+    pub fn to_kick(&self) -> Result<KickSessions, GenericError> {
+        if matches!(self.target, TargetType::Broadcast | TargetType::Transaction) {
+            return Err(GenericError::ValidationError(format!(
+                "{:?} target cannot be kicked",
+                self.target
+            )));
+        }
+        let code = self.code.unwrap_or(DEFAULT_KICK_CODE);
+        if !matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999) {
+            return Err(GenericError::ValidationError(format!(
+                "{} is not a close code that can be sent",
+                code
+            )));
+        }
+        // the close frame payload is limited to 125 bytes including the code
+        if self
+            .reason
+            .as_ref()
+            .is_some_and(|reason| reason.len() > 123)
+        {
+            return Err(GenericError::ValidationError(
+                "reason is longer than 123 bytes".to_string(),
+            ));
+        }
+        Ok(KickSessions {
+            id: (self.target == TargetType::Exact).then(|| self.get_ws_key()),
+            target: resolve_target(&self.target, self.user_id, self.business_id, None)?,
+            reason: CloseReason {
+                code: CloseCode::from(code),
+                description: self.reason.clone(),
+            },
+        })
+    }
+}
+
+impl WSKeyTrait for KickRequest {
+    fn get_ws_key(&self) -> String {
+        format!(
+            "{}#{}#{}",
+            self.user_id.map_or("NA".to_string(), |id| id.to_string()),
+            self.business_id
+                .map_or("NA".to_string(), |id| id.to_string()),
+            self.device_id.clone().unwrap_or("NA".to_string())
+        )
+    }
+}
+
+impl FromRequest for KickRequest {
+    type Error = GenericError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let fut = web::Json::<Self>::from_request(req, payload);
+
+        Box::pin(async move {
+            match fut.await {
+                Ok(json) => Ok(json.into_inner()),
+                Err(e) => Err(GenericError::ValidationError(e.to_string())),
+            }
+        })
+    }
+}
+
+/// Admin results of the replica that served the request, each replica only sees its own sessions.
+#[derive(Serialize, Debug, ToSchema)]
+pub struct InstanceResult<T> {
+    pub instance_id: String,
+    pub result: T,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct KickResult {
+    /// Connections sent a close frame, on all instances that answered
+    pub closed: usize,
+    /// Instances holding the sessions that didn't answer in time
+    pub unconfirmed: Vec<String>,
+}
+
+/// Most items accepted by `/send/batch`.
+pub const MAX_BATCH_SIZE: usize = 500;
+
+/// Body of `/send/batch`, items are parsed one by one so a bad item fails alone.
+#[derive(Debug)]
+pub struct WSBatchRequest(pub Vec<Value>);
+
+impl FromRequest for WSBatchRequest {
+    type Error = GenericError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let fut = web::Json::<Vec<Value>>::from_request(req, payload);
+
+        Box::pin(async move {
+            let items = match fut.await {
+                Ok(json) => json.into_inner(),
+                Err(e) => return Err(GenericError::ValidationError(e.to_string())),
+            };
+            if items.len() > MAX_BATCH_SIZE {
+                return Err(GenericError::ValidationError(format!(
+                    "A batch can have at most {} items",
+                    MAX_BATCH_SIZE
+                )));
+            }
+            Ok(Self(items))
+        })
+    }
+}
+
+#[derive(Serialize, Debug, ToSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchItemStatus {
+    /// Delivered or published
+    Accepted,
+    /// Malformed or failed validation
+    Invalid,
+    /// Valid but not allowed or not published
+    Rejected,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct BatchItemResult {
+    /// Position of the item in the request
+    pub index: usize,
+    pub status: BatchItemStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl BatchItemResult {
+    pub fn new(index: usize, result: Result<(), GenericError>) -> Self {
+        let (status, message) = match result {
+            Ok(_) => (BatchItemStatus::Accepted, None),
+            Err(e @ GenericError::ValidationError(_)) => {
+                (BatchItemStatus::Invalid, Some(e.to_string()))
+            }
+            Err(e) => (BatchItemStatus::Rejected, Some(e.to_string())),
+        };
+        Self {
+            index,
+            status,
+            message,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct BatchSendResponse {
+    pub status: bool,
+    pub customer_message: String,
+    pub code: String,
+    pub results: Vec<BatchItemResult>,
+}
+
+impl BatchSendResponse {
+    pub fn new(results: Vec<BatchItemResult>) -> Self {
+        let accepted = results
+            .iter()
+            .filter(|r| r.status == BatchItemStatus::Accepted)
+            .count();
+        Self {
+            status: accepted == results.len(),
+            customer_message: format!("{} of {} items accepted", accepted, results.len()),
+            code: String::from("200"),
+            results,
+        }
+    }
+}
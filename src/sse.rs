@@ -0,0 +1,225 @@
+use crate::schemas::{WSKeyTrait, WebSocketParam};
+use crate::websocket::{
+    Activity, ClientAck, ClientInfo, CloseSession, Connect, Disconnect, Message, PayloadMeta,
+    Server, SessionActivity,
+};
+use actix::{
+    fut, Actor, ActorContext, ActorFutureExt, Addr, AsyncContext, Context, ContextFutureSpawner,
+    Handler, Running, WrapFuture,
+};
+use actix_web::web::Bytes;
+use futures::channel::mpsc;
+use futures::{Stream, StreamExt};
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Comment lines keep proxies from closing an idle stream and detect clients that went away.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+/// Events waiting to be written, a client reading slower than that is disconnected.
+const EVENT_QUEUE_SIZE: usize = 256;
+
+/// An event and the id of the message it carries, acked once written.
+type Frame = (Bytes, Option<Uuid>);
+
+/// Formats a Server-Sent Event, `data` is split over several lines when it has line breaks.
+fn event(id: Option<u64>, name: Option<&str>, data: &str) -> Bytes {
+    let mut event = String::new();
+    if let Some(id) = id {
+        event.push_str(&format!("id: {}\n", id));
+    }
+    if let Some(name) = name {
+        event.push_str(&format!("event: {}\n", name));
+    }
+    for line in data.lines() {
+        event.push_str(&format!("data: {}\n", line));
+    }
+    event.push('\n');
+    Bytes::from(event)
+}
+
+/// Server-Sent Events connection, registered with `Server` like a `WebSocketSession` so `/send`
+/// reaches it the same way. The stream is one-way: messages are acked by `EventStream` once
+/// written to the connection.
+pub struct SseSession {
+    id: String,
+    conn_id: Uuid,
+    user_id: Option<Uuid>,
+    business_id: Option<Uuid>,
+    last_seen: Option<u64>,
+    client: ClientInfo,
+    server_addr: Addr<Server>,
+    sender: mpsc::Sender<Frame>,
+}
+
+impl SseSession {
+    /// Returns the session and the body of the event stream, `last_event_id` takes precedence
+    /// over the `last_seen` parameter when replaying buffered messages.
+    pub fn new(
+        param: &WebSocketParam,
+        last_event_id: Option<u64>,
+        client: ClientInfo,
+        server_addr: Addr<Server>,
+    ) -> (Self, EventStream) {
+        let (sender, receiver) = mpsc::channel(EVENT_QUEUE_SIZE);
+        let conn_id = Uuid::new_v4();
+        let events = EventStream {
+            receiver,
+            conn_id,
+            server_addr: server_addr.clone(),
+            written: vec![],
+            flushing: vec![],
+        };
+        let session = Self {
+            id: param.get_ws_key(),
+            conn_id,
+            user_id: param.user_id,
+            business_id: param.business_id,
+            last_seen: last_event_id.or(param.last_seen),
+            client,
+            server_addr,
+            sender,
+        };
+        (session, events)
+    }
+
+    /// Queues an event, stops the session once the client has gone away or fell too far behind.
+    /// Messages of a stopped session are never acked, so they are redelivered or buffered.
+    fn write(&mut self, frame: Frame, ctx: &mut Context<Self>) -> bool {
+        match self.sender.try_send(frame) {
+            Ok(_) => true,
+            Err(err) => {
+                if err.is_full() {
+                    warn!("Event stream client is too slow, disconnecting!");
+                } else {
+                    info!("Event stream client went away, disconnecting!");
+                }
+                ctx.stop();
+                false
+            }
+        }
+    }
+
+    fn keepalive(&self, ctx: &mut Context<Self>) {
+        ctx.run_interval(KEEPALIVE_INTERVAL, |act, ctx| {
+            if act.write((Bytes::from_static(b": keepalive\n\n"), None), ctx) {
+                act.server_addr.do_send(SessionActivity {
+                    conn_id: act.conn_id,
+                    activity: Activity::Heartbeat,
+                });
+            }
+        });
+    }
+}
+
+impl Actor for SseSession {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.keepalive(ctx);
+
+        let session_addr = ctx.address();
+        self.server_addr
+            .send(Connect {
+                addr: session_addr.clone().recipient(),
+                id: self.id.clone(),
+                conn_id: self.conn_id,
+                user_id: self.user_id,
+                business_id: self.business_id,
+                last_seen: self.last_seen,
+                client: self.client.clone(),
+                closer: session_addr.recipient(),
+            })
+            .into_actor(self)
+            .then(|res, _act, ctx| {
+                if res.is_err() {
+                    ctx.stop();
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        self.server_addr.do_send(Disconnect {
+            id: self.id.clone(),
+            conn_id: self.conn_id,
+            user_id: self.user_id,
+            business_id: self.business_id,
+        });
+        Running::Stop
+    }
+}
+
+impl Handler<Message> for SseSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, ctx: &mut Self::Context) {
+        let meta = PayloadMeta::parse(&msg.0);
+        self.write((event(meta.sequence, None, &msg.0), meta.message_id), ctx);
+    }
+}
+
+/// Sends a `close` event with the code and reason, then ends the stream.
+impl Handler<CloseSession> for SseSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: CloseSession, ctx: &mut Self::Context) {
+        info!("Closing event stream: {:?}", msg.0);
+        let data = serde_json::json!({
+            "code": u16::from(msg.0.code),
+            "reason": msg.0.description,
+        });
+        if self
+            .sender
+            .try_send((event(None, Some("close"), &data.to_string()), None))
+            .is_err()
+        {
+            warn!("Event stream client already went away or is too slow");
+        }
+        ctx.stop();
+    }
+}
+
+/// Body of the event stream response. Messages are acked on the poll after the response waited
+/// for more, it flushes what it was handed in between.
+pub struct EventStream {
+    receiver: mpsc::Receiver<Frame>,
+    conn_id: Uuid,
+    server_addr: Addr<Server>,
+    /// Messages handed to the response since it last waited
+    written: Vec<Uuid>,
+    /// Messages the response is flushing, acked on the next poll
+    flushing: Vec<Uuid>,
+}
+
+impl Stream for EventStream {
+    type Item = Bytes;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Bytes>> {
+        for message_id in std::mem::take(&mut self.flushing) {
+            self.server_addr.do_send(ClientAck {
+                conn_id: self.conn_id,
+                message_id,
+            });
+        }
+        match self.receiver.poll_next_unpin(cx) {
+            Poll::Ready(Some((data, message_id))) => {
+                self.written.extend(message_id);
+                Poll::Ready(Some(data))
+            }
+            // messages not flushed when the client leaves are never acked
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => {
+                if !self.written.is_empty() {
+                    self.flushing = std::mem::take(&mut self.written);
+                    // polled again once the response has flushed
+                    cx.waker().wake_by_ref();
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -1,13 +1,17 @@
-use crate::middlewares::SaveRequestResponse;
-use crate::pulsar_client::AppState;
+use crate::broker::{start_consumer, AppState, InboundPublisher, PendingKicks, Source};
+use crate::long_poll::Mailboxes;
+use crate::middlewares::{PathOnlyRootSpan, SaveRequestResponse};
+use crate::presence::{start_presence, PresenceDirectory};
 use crate::routes::routes;
 use crate::schemas::Settings;
+use crate::utils::get_instance_id;
 use crate::websocket;
 use actix::Actor;
 use actix_web::dev::Server;
 use actix_web::{web, App, HttpServer};
 use std::net::TcpListener;
-use tokio::sync::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
 use tracing_actix_web::TracingLogger;
 pub struct Application {
     port: u16,
@@ -35,29 +39,63 @@ impl Application {
 async fn run(listener: TcpListener, configuration: Settings) -> Result<Server, anyhow::Error> {
     let secret_obj = web::Data::new(configuration.secret);
     let workers = configuration.application.workers;
+    let instance_id = get_instance_id(configuration.application.instance_id.as_deref());
     let application_obj = web::Data::new(configuration.application);
-    let pulsar = configuration.pulsar.client().await?;
-    let producer = pulsar.get_producer().await;
-    let consumer = pulsar
-        .get_consumer("test_consumer".to_owned(), "test_subscription".to_owned())
-        .await;
-    let pulsar_prod = web::Data::new(AppState {
-        producer: Mutex::new(producer),
+    let validation_obj = web::Data::new(configuration.validation);
+    let broker = configuration
+        .broker
+        .build(
+            &instance_id,
+            configuration.pulsar.as_ref(),
+            configuration.redis.as_ref(),
+            configuration.kafka.as_ref(),
+        )
+        .await?;
+    let directory = PresenceDirectory::default();
+    let presence_notifier = start_presence(broker.clone(), &instance_id, directory.clone());
+    let app_state = Arc::new(AppState {
+        broker: broker.clone(),
+        directory,
+        instance_id: instance_id.clone(),
+        kicks: PendingKicks::default(),
     });
+    let pulsar_prod = web::Data::from(app_state.clone());
+    let inbound_publisher = web::Data::new(InboundPublisher::start(broker.clone()));
     // let pulsar_prod = web::Data::new(producer);
 
-    let ws_server = web::Data::new(websocket::Server::new().start());
+    let buffer = configuration.buffer.build()?;
+    let ws_server = web::Data::new(
+        websocket::Server::new(buffer, Duration::from_secs(configuration.buffer.ttl_secs))
+            .with_presence(presence_notifier)
+            .start(),
+    );
+    let mailboxes = web::Data::new(Mailboxes::new().start());
 
-    pulsar.start_consumer(consumer, ws_server.clone()).await;
+    let redelivery_policy = configuration.broker.redelivery_policy();
+    start_consumer(
+        Source::Shared,
+        app_state.clone(),
+        ws_server.get_ref().clone(),
+        redelivery_policy.clone(),
+    );
+    start_consumer(
+        Source::Instance(instance_id),
+        app_state,
+        ws_server.get_ref().clone(),
+        redelivery_policy,
+    );
     let server = HttpServer::new(move || {
         App::new()
             //.app_data(web::JsonConfig::default().limit(1024 * 1024 * 50))
             .wrap(SaveRequestResponse)
-            .wrap(TracingLogger::default())
+            .wrap(TracingLogger::<PathOnlyRootSpan>::new())
             .app_data(secret_obj.clone())
             .app_data(application_obj.clone())
+            .app_data(validation_obj.clone())
             .app_data(ws_server.clone())
+            .app_data(mailboxes.clone())
             .app_data(pulsar_prod.clone())
+            .app_data(inbound_publisher.clone())
             // .app_data(pulsar_consumer.clone())
             .configure(routes)
     })
@@ -1,11 +0,0 @@
-#[cfg(test)]
-mod tests {
-    // use uuid::Uuid;
-
-    // pub async fn get_test_pool() -> PgPool {
-    //     let mut configuration = get_configuration().expect("Failed to read configuration.");
-    //     configuration.application.port = 0;
-    //     get_connection_pool(&configuration.database)
-    // }
-
-}
\ No newline at end of file
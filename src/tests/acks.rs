@@ -0,0 +1,33 @@
+use super::{assert_quiet, next_message, request, Harness};
+use crate::websocket::ClientAck;
+
+#[actix_web::test]
+async fn acked_message_is_delivered_once() {
+    let harness = Harness::start(3).await;
+    let request = request();
+    let (_, mut received) = harness.connect(&request, None, true).await;
+
+    harness.send(&request).await;
+
+    assert!(next_message(&mut received).await.message_id.is_some());
+    assert_quiet(&mut received).await;
+    assert!(harness.broker.dead_letters().is_empty());
+}
+
+#[actix_web::test]
+async fn ack_from_another_connection_is_ignored() {
+    let harness = Harness::start(5).await;
+    let request = request();
+    let (_, mut received) = harness.connect(&request, None, false).await;
+    let (other_conn_id, _other) = harness.connect(&self::request(), None, false).await;
+
+    harness.send(&request).await;
+
+    let first = next_message(&mut received).await;
+    harness.server.do_send(ClientAck {
+        conn_id: other_conn_id,
+        message_id: first.message_id.unwrap(),
+    });
+    let second = next_message(&mut received).await;
+    assert_eq!(first.message_id, second.message_id);
+}
@@ -0,0 +1,99 @@
+use super::{authorization, request, Harness};
+use crate::schemas::{WSKeyTrait, ADMIN_SCOPE, SEND_SCOPE};
+use actix_web::test::{self as actix_test, TestRequest};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+async fn get(harness: &Harness, uri: &str) -> Value {
+    let response = harness
+        .call(
+            TestRequest::get()
+                .uri(uri)
+                .insert_header(authorization(&[ADMIN_SCOPE])),
+        )
+        .await;
+    assert!(response.status().is_success());
+    actix_test::read_body_json(response).await
+}
+
+#[actix_web::test]
+async fn sessions_are_listed_with_the_instance() {
+    let harness = Harness::new();
+    let (request, other) = (request(), request());
+    let (conn_id, _) = harness.connect(&request, None, true).await;
+    harness.connect(&other, None, true).await;
+
+    let body = get(
+        &harness,
+        &format!(
+            "/admin/sessions?business_id={}",
+            request.business_id.unwrap()
+        ),
+    )
+    .await;
+
+    assert_eq!(body["data"]["instance_id"], "test");
+    let sessions = body["data"]["result"].as_array().unwrap();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0]["conn_id"], json!(conn_id));
+}
+
+#[actix_web::test]
+async fn session_details_are_shown_by_connection() {
+    let harness = Harness::new();
+    let request = request();
+    let (conn_id, _) = harness.connect(&request, None, true).await;
+
+    let body = get(&harness, &format!("/admin/sessions/{}", conn_id)).await;
+
+    assert_eq!(body["data"]["instance_id"], "test");
+    assert_eq!(
+        body["data"]["result"]["ws_key"],
+        json!(request.get_ws_key())
+    );
+}
+
+#[actix_web::test]
+async fn unknown_session_is_not_found() {
+    let harness = Harness::new();
+
+    let response = harness
+        .call(
+            TestRequest::get()
+                .uri(&format!("/admin/sessions/{}", Uuid::new_v4()))
+                .insert_header(authorization(&[ADMIN_SCOPE])),
+        )
+        .await;
+
+    assert_eq!(response.status(), 400);
+}
+
+#[actix_web::test]
+async fn sessions_are_counted_per_business() {
+    let harness = Harness::new();
+    let request = request();
+    harness.connect(&request, None, true).await;
+    harness.connect(&request, None, true).await;
+
+    let body = get(&harness, "/admin/sessions/count").await;
+
+    assert_eq!(
+        body["data"]["result"],
+        json!([{"business_id": request.business_id, "sessions": 2}])
+    );
+}
+
+#[actix_web::test]
+async fn admin_api_needs_the_admin_scope() {
+    let harness = Harness::new();
+
+    let response = harness
+        .call(
+            TestRequest::get()
+                .uri("/admin/sessions")
+                .insert_header(authorization(&[SEND_SCOPE])),
+        )
+        .await;
+
+    assert_eq!(response.status(), 403);
+}
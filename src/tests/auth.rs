@@ -0,0 +1,73 @@
+use crate::errors::GenericError;
+use crate::schemas::{JWTClaims, WebSocketParam, ADMIN_SCOPE};
+use uuid::Uuid;
+
+fn claims(sub: &str, business_id: Option<Uuid>, scopes: &[&str]) -> JWTClaims {
+    JWTClaims {
+        sub: sub.to_string(),
+        exp: usize::MAX,
+        business_id,
+        scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+    }
+}
+
+fn param(user_id: Option<Uuid>, business_id: Option<Uuid>, device_id: &str) -> WebSocketParam {
+    WebSocketParam {
+        user_id,
+        business_id,
+        device_id: Some(device_id.to_string()),
+        token: None,
+        last_seen: None,
+    }
+}
+
+#[test]
+fn token_of_the_user_and_business_is_accepted() {
+    let (user_id, business_id) = (Uuid::new_v4(), Uuid::new_v4());
+    let claims = claims(&user_id.to_string(), Some(business_id), &[]);
+
+    let result = param(Some(user_id), Some(business_id), "device").validate_claims(&claims);
+
+    assert!(result.is_ok());
+}
+
+#[test]
+fn token_of_another_user_is_rejected() {
+    let business_id = Uuid::new_v4();
+    let claims = claims(&Uuid::new_v4().to_string(), Some(business_id), &[]);
+
+    let result = param(Some(Uuid::new_v4()), Some(business_id), "device").validate_claims(&claims);
+
+    assert!(matches!(result, Err(GenericError::InvalidJWT(_))));
+}
+
+#[test]
+fn token_of_another_business_is_rejected() {
+    let user_id = Uuid::new_v4();
+    let claims = claims(&user_id.to_string(), Some(Uuid::new_v4()), &[]);
+
+    let result = param(Some(user_id), Some(Uuid::new_v4()), "device").validate_claims(&claims);
+
+    assert!(matches!(result, Err(GenericError::InvalidJWT(_))));
+}
+
+#[test]
+fn device_only_key_needs_the_device_as_subject() {
+    let business_only = claims("service", Some(Uuid::new_v4()), &[]);
+    let device = claims("device", None, &[]);
+
+    assert!(matches!(
+        param(None, None, "device").validate_claims(&business_only),
+        Err(GenericError::InvalidJWT(_))
+    ));
+    assert!(param(None, None, "device").validate_claims(&device).is_ok());
+}
+
+#[test]
+fn device_only_key_is_allowed_for_admins() {
+    let claims = claims("service", None, &[ADMIN_SCOPE]);
+
+    let result = param(None, None, "device").validate_claims(&claims);
+
+    assert!(result.is_ok());
+}
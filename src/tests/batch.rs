@@ -0,0 +1,66 @@
+use super::{authorization, next_message, request, Harness};
+use crate::schemas::{WSRequest, SEND_SCOPE};
+use actix_web::test::{self as actix_test, TestRequest};
+use serde_json::{json, Value};
+
+fn item(request: &WSRequest, target: &str) -> Value {
+    json!({
+        "user_id": request.user_id,
+        "business_id": request.business_id,
+        "device_id": request.device_id,
+        "action_type": "info",
+        "data": {"text": "hello"},
+        "target": target,
+    })
+}
+
+#[actix_web::test]
+async fn batch_items_get_their_own_result() {
+    let harness = Harness::start(3).await;
+    let request = request();
+    let (_, mut received) = harness.connect(&request, None, true).await;
+    let items = json!([
+        item(&request, "Exact"),
+        {"action_type": "info"},
+        item(&request, "Broadcast"),
+    ]);
+
+    let response = harness
+        .call(
+            TestRequest::post()
+                .uri("/send/batch")
+                .insert_header(authorization(&[SEND_SCOPE]))
+                .set_json(items),
+        )
+        .await;
+
+    assert!(response.status().is_success());
+    let body: Value = actix_test::read_body_json(response).await;
+    let statuses: Vec<&Value> = body["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|result| &result["status"])
+        .collect();
+    assert_eq!(
+        statuses,
+        [&json!("accepted"), &json!("invalid"), &json!("rejected")]
+    );
+    assert!(next_message(&mut received).await.message_id.is_some());
+}
+
+#[actix_web::test]
+async fn batch_without_the_send_scope_is_forbidden() {
+    let harness = Harness::start(3).await;
+
+    let response = harness
+        .call(
+            TestRequest::post()
+                .uri("/send/batch")
+                .insert_header(authorization(&[]))
+                .set_json(json!([])),
+        )
+        .await;
+
+    assert_eq!(response.status(), 403);
+}
@@ -0,0 +1,36 @@
+use super::{assert_quiet, next_message, request, Harness};
+use crate::errors::GenericError;
+use crate::schemas::{TargetType, BROADCAST_SCOPE};
+
+#[actix_web::test]
+async fn broadcast_without_the_broadcast_scope_is_rejected() {
+    let harness = Harness::start(3).await;
+    let mut request = request();
+    let (_, mut received) = harness.connect(&request, None, true).await;
+
+    request.target = TargetType::Broadcast;
+    let result = harness.try_send(&request).await;
+
+    assert!(matches!(
+        result,
+        Err(GenericError::InsufficientPrivilege(_))
+    ));
+    assert_quiet(&mut received).await;
+}
+
+#[actix_web::test]
+async fn broadcast_reaches_every_business() {
+    let harness = Harness::start(3).await;
+    let (mut broadcast, other) = (request(), request());
+    let (_, mut first) = harness.connect(&broadcast, None, true).await;
+    let (_, mut second) = harness.connect(&other, None, true).await;
+
+    broadcast.target = TargetType::Broadcast;
+    harness
+        .try_send_as(&broadcast, &[BROADCAST_SCOPE])
+        .await
+        .unwrap();
+
+    let message_id = next_message(&mut first).await.message_id;
+    assert_eq!(next_message(&mut second).await.message_id, message_id);
+}
@@ -0,0 +1,30 @@
+use super::{assert_quiet, next_message, request, Harness};
+
+#[actix_web::test]
+async fn offline_messages_are_buffered_and_replayed_after_last_seen() {
+    let harness = Harness::start(3).await;
+    let request = request();
+
+    harness.send_offline(&request).await;
+    harness.send_offline(&request).await;
+
+    // buffered messages are acked on the broker, they don't come back as redeliveries
+    let (conn_id, mut received) = harness.connect(&request, None, true).await;
+    let first = next_message(&mut received).await;
+    let second = next_message(&mut received).await;
+    assert!(second.sequence > first.sequence);
+    assert_quiet(&mut received).await;
+    harness.disconnect(&request, conn_id).await;
+
+    harness.send_offline(&request).await;
+    let (conn_id, mut received) = harness.connect(&request, second.sequence, true).await;
+    let third = next_message(&mut received).await;
+    assert!(third.sequence > second.sequence);
+    harness.disconnect(&request, conn_id).await;
+
+    // already seen, dropped on replay
+    harness.send_offline(&request).await;
+    let (_, mut received) = harness.connect(&request, Some(u64::MAX), true).await;
+    assert_quiet(&mut received).await;
+    assert!(harness.broker.dead_letters().is_empty());
+}
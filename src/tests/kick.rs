@@ -0,0 +1,76 @@
+use super::{authorization, request, Harness, WAIT};
+use crate::broker::Source;
+use crate::schemas::{WSRequest, ADMIN_SCOPE};
+use actix_web::test::{self as actix_test, TestRequest};
+use serde_json::{json, Value};
+use tokio::sync::mpsc::{self, error::TryRecvError};
+
+async fn kick(harness: &Harness, request: &WSRequest, code: u16) -> (u16, Value) {
+    let response = harness
+        .call(
+            TestRequest::post()
+                .uri("/admin/sessions/kick")
+                .insert_header(authorization(&[ADMIN_SCOPE]))
+                .set_json(json!({
+                    "user_id": request.user_id,
+                    "business_id": request.business_id,
+                    "device_id": request.device_id,
+                    "code": code,
+                    "reason": "logged out",
+                })),
+        )
+        .await;
+    let status = response.status().as_u16();
+    (status, actix_test::read_body_json(response).await)
+}
+
+/// The client stopped, dropping its end of the channel.
+async fn assert_closed(receiver: &mut mpsc::UnboundedReceiver<String>) {
+    let received = tokio::time::timeout(WAIT, receiver.recv()).await;
+    assert!(matches!(received, Ok(None)), "not closed: {:?}", received);
+}
+
+#[actix_web::test]
+async fn kick_closes_the_sessions_of_the_key() {
+    let harness = Harness::new();
+    let request = request();
+    let (_, mut received) = harness.connect(&request, None, true).await;
+
+    let (status, body) = kick(&harness, &request, 4001).await;
+
+    assert_eq!(status, 200);
+    assert_eq!(body["data"], json!({"closed": 1, "unconfirmed": []}));
+    assert_closed(&mut received).await;
+}
+
+#[actix_web::test]
+async fn kick_reaches_sessions_on_another_instance() {
+    let harness = Harness::new();
+    harness
+        .consume(Source::Instance("test".to_string()), 3)
+        .await;
+    let peer = harness.peer("other");
+    peer.consume(Source::Instance("other".to_string()), 3).await;
+    let request = request();
+    let (_, mut received) = peer.connect(&request, None, true).await;
+    harness.announce(&request, "other");
+
+    let (status, body) = kick(&harness, &request, 4001).await;
+
+    assert_eq!(status, 200);
+    assert_eq!(body["data"], json!({"closed": 1, "unconfirmed": []}));
+    assert_closed(&mut received).await;
+}
+
+#[actix_web::test]
+async fn kick_with_a_reserved_close_code_is_invalid() {
+    let harness = Harness::new();
+    let request = request();
+    let (_, mut received) = harness.connect(&request, None, true).await;
+
+    let (status, _) = kick(&harness, &request, 1006).await;
+
+    assert_eq!(status, 400);
+    // still open, nothing was sent
+    assert!(matches!(received.try_recv(), Err(TryRecvError::Empty)));
+}
@@ -0,0 +1,351 @@
+//! Drives `/send` messages through the memory broker, the consumer and `Server` to a client.
+
+mod acks;
+mod admin;
+mod auth;
+mod batch;
+mod broadcast;
+mod buffering;
+mod kick;
+mod poll;
+mod redelivery;
+mod routing;
+mod send;
+mod sessions;
+mod sse;
+mod targets;
+mod transactions;
+
+use crate::broker::{start_consumer, AppState, PendingKicks, RedeliveryPolicy, Source};
+use crate::buffer::MemoryBuffer;
+use crate::errors::GenericError;
+use crate::handlers::send_message;
+use crate::long_poll::Mailboxes;
+use crate::memory_broker::MemoryBroker;
+use crate::presence::{PresenceDirectory, PresenceEvent};
+use crate::routes::routes;
+use crate::schemas::{JWTClaims, Jwt, SecretSetting, ValidationSetting, WSKeyTrait, WSRequest};
+use crate::utils::generate_jwt_token_for_user;
+use crate::websocket::{
+    ClientAck, ClientInfo, CloseSession, Connect, Disconnect, Message, PayloadMeta, Server,
+    SessionExists,
+};
+use actix::{Actor, ActorContext, Addr, Context, Handler};
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{HeaderName, AUTHORIZATION};
+use actix_web::test::{self as actix_test, TestRequest};
+use actix_web::{web, App};
+use chrono::Utc;
+use secrecy::{ExposeSecret, SecretString};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+const ACK_TIMEOUT: Duration = Duration::from_millis(100);
+/// Longest a test waits for something to happen
+const WAIT: Duration = Duration::from_secs(5);
+const JWT_SECRET: &str = "secret";
+
+/// Stands in for a socket, handing each message to the test and acking it when asked to.
+struct TestClient {
+    conn_id: Uuid,
+    server: Addr<Server>,
+    acks: bool,
+    received: mpsc::UnboundedSender<String>,
+}
+
+impl Actor for TestClient {
+    type Context = Context<Self>;
+}
+
+impl Handler<Message> for TestClient {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, _: &mut Self::Context) {
+        if let Some(message_id) = PayloadMeta::parse(&msg.0).message_id.filter(|_| self.acks) {
+            self.server.do_send(ClientAck {
+                conn_id: self.conn_id,
+                message_id,
+            });
+        }
+        let _ = self.received.send(msg.0);
+    }
+}
+
+impl Handler<CloseSession> for TestClient {
+    type Result = ();
+
+    fn handle(&mut self, _: CloseSession, ctx: &mut Self::Context) {
+        ctx.stop();
+    }
+}
+
+struct Harness {
+    broker: Arc<MemoryBroker>,
+    app_state: Arc<AppState>,
+    server: Addr<Server>,
+    mailboxes: Addr<Mailboxes>,
+}
+
+impl Harness {
+    /// Without consumers, nothing reads the broker yet.
+    fn new() -> Self {
+        Self::instance(
+            Arc::new(MemoryBroker::default()),
+            PresenceDirectory::default(),
+            "test",
+        )
+    }
+
+    fn instance(
+        broker: Arc<MemoryBroker>,
+        directory: PresenceDirectory,
+        instance_id: &str,
+    ) -> Self {
+        let app_state = Arc::new(AppState {
+            broker: broker.clone(),
+            directory,
+            instance_id: instance_id.to_string(),
+            kicks: PendingKicks::default(),
+        });
+        let server = Server::new(Box::new(MemoryBuffer::default()), Duration::from_secs(60))
+            .with_ack_timeout(ACK_TIMEOUT)
+            .start();
+        Self {
+            broker,
+            app_state,
+            server,
+            mailboxes: Mailboxes::new().start(),
+        }
+    }
+
+    /// Another instance on the same broker and presence directory.
+    fn peer(&self, instance_id: &str) -> Self {
+        Self::instance(
+            self.broker.clone(),
+            self.app_state.directory.clone(),
+            instance_id,
+        )
+    }
+
+    /// Records the key of `request` as held by `instance_id`, like its presence events would.
+    fn announce(&self, request: &WSRequest, instance_id: &str) {
+        let incarnation = Uuid::new_v4();
+        self.app_state.directory.apply(PresenceEvent::Heartbeat {
+            instance_id: instance_id.to_string(),
+            incarnation,
+            sent_at: Utc::now().timestamp_millis(),
+        });
+        self.app_state.directory.apply(PresenceEvent::Key {
+            key: request.get_ws_key(),
+            instance_id: instance_id.to_string(),
+            incarnation,
+            online: true,
+        });
+    }
+
+    /// With a consumer of the shared topic.
+    async fn start(max_redelivery: u32) -> Self {
+        let harness = Self::new();
+        harness.consume(Source::Shared, max_redelivery).await;
+        harness
+    }
+
+    async fn consume(&self, source: Source, max_redelivery: u32) {
+        let policy = RedeliveryPolicy {
+            max_redelivery,
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+        };
+        start_consumer(
+            source.clone(),
+            self.app_state.clone(),
+            self.server.clone(),
+            policy,
+        );
+        wait_until(|| self.broker.is_subscribed(&source)).await;
+    }
+
+    /// Connects a client under the key of `request`, returning what it receives.
+    async fn connect(
+        &self,
+        request: &WSRequest,
+        last_seen: Option<u64>,
+        acks: bool,
+    ) -> (Uuid, mpsc::UnboundedReceiver<String>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let conn_id = Uuid::new_v4();
+        let client = TestClient {
+            conn_id,
+            server: self.server.clone(),
+            acks,
+            received: sender,
+        }
+        .start();
+        self.server
+            .send(Connect {
+                addr: client.clone().recipient(),
+                id: request.get_ws_key(),
+                conn_id,
+                user_id: request.user_id,
+                business_id: request.business_id,
+                last_seen,
+                client: ClientInfo::default(),
+                closer: client.recipient(),
+            })
+            .await
+            .unwrap();
+        (conn_id, receiver)
+    }
+
+    async fn disconnect(&self, request: &WSRequest, conn_id: Uuid) {
+        self.server
+            .send(Disconnect {
+                id: request.get_ws_key(),
+                conn_id,
+                user_id: request.user_id,
+                business_id: request.business_id,
+            })
+            .await
+            .unwrap();
+    }
+
+    async fn send(&self, request: &WSRequest) {
+        self.try_send(request).await.unwrap();
+    }
+
+    async fn try_send(&self, request: &WSRequest) -> Result<(), GenericError> {
+        self.try_send_as(request, &[]).await
+    }
+
+    /// Sends with a token that has `scopes`.
+    async fn try_send_as(&self, request: &WSRequest, scopes: &[&str]) -> Result<(), GenericError> {
+        let claims = JWTClaims {
+            sub: "service".to_string(),
+            exp: usize::MAX,
+            business_id: None,
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+        };
+        send_message(
+            request,
+            &claims,
+            &self.server,
+            &self.app_state,
+            &ValidationSetting::default(),
+        )
+        .await
+    }
+
+    /// Waits for a session of another transport to register the key of `request`.
+    async fn wait_for_session(&self, request: &WSRequest) {
+        tokio::time::timeout(WAIT, async {
+            let exists = || SessionExists {
+                id: request.get_ws_key(),
+            };
+            while !self.server.send(exists()).await.unwrap() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("no session in time");
+    }
+
+    /// Calls the HTTP API, with the routes and app data of the service.
+    async fn call(&self, request: TestRequest) -> ServiceResponse {
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(secret()))
+                .app_data(web::Data::new(ValidationSetting::default()))
+                .app_data(web::Data::new(self.server.clone()))
+                .app_data(web::Data::new(self.mailboxes.clone()))
+                .app_data(web::Data::from(self.app_state.clone()))
+                .configure(routes),
+        )
+        .await;
+        actix_test::call_service(&app, request.to_request()).await
+    }
+
+    /// Sends to a key nobody is connected to and gives the consumer time to buffer it.
+    async fn send_offline(&self, request: &WSRequest) {
+        self.send(request).await;
+        tokio::time::sleep(ACK_TIMEOUT).await;
+    }
+
+    async fn dead_letter(&self) -> (String, String) {
+        wait_until(|| !self.broker.dead_letters().is_empty()).await;
+        self.broker.dead_letters().remove(0)
+    }
+}
+
+fn secret() -> SecretSetting {
+    SecretSetting {
+        jwt: Jwt {
+            secret: SecretString::from(JWT_SECRET),
+            expiry: 1,
+        },
+    }
+}
+
+fn token(sub: &str, business_id: Option<Uuid>, scopes: &[&str]) -> String {
+    let scopes = scopes.iter().map(|scope| scope.to_string()).collect();
+    generate_jwt_token_for_user(sub, business_id, scopes, 1, &secret().jwt.secret)
+        .unwrap()
+        .expose_secret()
+        .to_string()
+}
+
+/// `Authorization` header of a service token with `scopes`.
+fn authorization(scopes: &[&str]) -> (HeaderName, String) {
+    (
+        AUTHORIZATION,
+        format!("Bearer {}", token("service", None, scopes)),
+    )
+}
+
+/// Query of `/events` and `/poll` for the key of `request`, with a token of its user.
+fn session_query(request: &WSRequest) -> String {
+    let user_id = request.user_id.unwrap();
+    format!(
+        "user_id={}&business_id={}&device_id=device&token={}",
+        user_id,
+        request.business_id.unwrap(),
+        token(&user_id.to_string(), request.business_id, &[])
+    )
+}
+
+fn request() -> WSRequest {
+    serde_json::from_value(json!({
+        "user_id": Uuid::new_v4(),
+        "business_id": Uuid::new_v4(),
+        "device_id": "device",
+        "action_type": "info",
+        "data": {"text": "hello"},
+    }))
+    .unwrap()
+}
+
+async fn wait_until(condition: impl Fn() -> bool) {
+    tokio::time::timeout(WAIT, async {
+        while !condition() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("condition not met in time");
+}
+
+async fn next_message(receiver: &mut mpsc::UnboundedReceiver<String>) -> PayloadMeta {
+    let payload = tokio::time::timeout(WAIT, receiver.recv())
+        .await
+        .expect("no message in time")
+        .unwrap();
+    PayloadMeta::parse(&payload)
+}
+
+/// Nothing arrives for a few ack timeouts.
+async fn assert_quiet(receiver: &mut mpsc::UnboundedReceiver<String>) {
+    let received = tokio::time::timeout(ACK_TIMEOUT * 5, receiver.recv()).await;
+    assert!(received.is_err(), "unexpected message: {:?}", received);
+}
@@ -0,0 +1,85 @@
+use super::{authorization, request, session_query, Harness, ACK_TIMEOUT};
+use crate::schemas::{WSRequest, ADMIN_SCOPE};
+use actix_web::test::{self as actix_test, TestRequest};
+use serde_json::{json, Value};
+
+/// Polls the key of `request`, `params` are appended to the query.
+async fn poll(harness: &Harness, request: &WSRequest, params: &str) -> Value {
+    let uri = format!("/poll?{}&{}", session_query(request), params);
+    let response = harness.call(TestRequest::get().uri(&uri)).await;
+    assert!(response.status().is_success());
+    let body: Value = actix_test::read_body_json(response).await;
+    body["data"].clone()
+}
+
+#[actix_web::test]
+async fn polled_messages_are_returned_until_the_cursor_passes_them() {
+    let harness = Harness::start(3).await;
+    let request = request();
+    let opened = poll(&harness, &request, "timeout_secs=0").await;
+    let session = opened["session"].as_str().unwrap().to_string();
+    assert_eq!(opened["messages"], json!([]));
+
+    harness.send(&request).await;
+    let first = poll(
+        &harness,
+        &request,
+        &format!("session={}&timeout_secs=5", session),
+    )
+    .await;
+    let again = poll(
+        &harness,
+        &request,
+        &format!("session={}&timeout_secs=0", session),
+    )
+    .await;
+    let cursor = first["cursor"].as_u64().unwrap();
+    let after = poll(
+        &harness,
+        &request,
+        &format!("session={}&cursor={}&timeout_secs=0", session, cursor),
+    )
+    .await;
+
+    let messages = first["messages"].as_array().unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0]["sequence"], json!(cursor));
+    assert_eq!(again["messages"], first["messages"]);
+    assert_eq!(after["messages"], json!([]));
+}
+
+#[actix_web::test]
+async fn kicked_mailbox_reports_the_close() {
+    let harness = Harness::new();
+    let request = request();
+    let opened = poll(&harness, &request, "timeout_secs=0").await;
+    let session = opened["session"].as_str().unwrap().to_string();
+    harness.wait_for_session(&request).await;
+
+    // the close is handed to the poll waiting when the kick arrives
+    let kick = async {
+        tokio::time::sleep(ACK_TIMEOUT).await;
+        harness
+            .call(
+                TestRequest::post()
+                    .uri("/admin/sessions/kick")
+                    .insert_header(authorization(&[ADMIN_SCOPE]))
+                    .set_json(json!({
+                        "user_id": request.user_id,
+                        "business_id": request.business_id,
+                        "device_id": "device",
+                        "code": 4001,
+                        "reason": "logged out",
+                    })),
+            )
+            .await
+    };
+    let params = format!("session={}&timeout_secs=5", session);
+    let waiting = poll(&harness, &request, &params);
+    let (closed, _) = futures::join!(waiting, kick);
+
+    assert_eq!(
+        closed["closed"],
+        json!({"code": 4001, "reason": "logged out"})
+    );
+}
@@ -0,0 +1,65 @@
+use super::{assert_quiet, next_message, request, Harness};
+use crate::broker::{Destination, MessageBroker, MessageData};
+use crate::schemas::BrokerSetting;
+use crate::websocket::ACK_TIMEOUT;
+use std::time::Duration;
+
+#[actix_web::test]
+async fn unacked_message_is_redelivered() {
+    let harness = Harness::start(5).await;
+    let request = request();
+    let (_, mut received) = harness.connect(&request, None, false).await;
+
+    harness.send(&request).await;
+
+    let first = next_message(&mut received).await;
+    let second = next_message(&mut received).await;
+    assert_eq!(first.message_id, second.message_id);
+    assert!(second.sequence > first.sequence);
+}
+
+#[actix_web::test]
+async fn message_is_dead_lettered_after_max_redelivery() {
+    let harness = Harness::start(1).await;
+    let request = request();
+    let (_, mut received) = harness.connect(&request, None, false).await;
+
+    harness.send(&request).await;
+
+    let first = next_message(&mut received).await;
+    let second = next_message(&mut received).await;
+    assert_eq!(first.message_id, second.message_id);
+    let (_, reason) = harness.dead_letter().await;
+    assert_eq!(reason, "Max redelivery exceeded");
+    assert_quiet(&mut received).await;
+}
+
+#[actix_web::test]
+async fn invalid_payload_is_dead_lettered() {
+    let harness = Harness::start(3).await;
+
+    harness
+        .broker
+        .publish(
+            Destination::Shared,
+            MessageData {
+                data: "not a message".to_string(),
+                partition_key: "key".to_string(),
+                deliver_at: None,
+                control: false,
+            },
+        )
+        .await
+        .unwrap();
+
+    let (_, reason) = harness.dead_letter().await;
+    assert!(reason.contains("expected"), "{}", reason);
+}
+
+#[test]
+fn broker_resends_after_the_ack_timeout_and_longest_backoff() {
+    let policy = BrokerSetting::default().redelivery_policy();
+
+    // 15s ack timeout, then up to 16s of backoff before the 5th redelivery is nacked
+    assert!(policy.resend_delay() > ACK_TIMEOUT + Duration::from_secs(16));
+}
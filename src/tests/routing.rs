@@ -0,0 +1,19 @@
+use super::{next_message, request, Harness};
+use crate::broker::Source;
+
+#[actix_web::test]
+async fn send_to_an_owned_key_goes_through_the_owner_topic() {
+    let harness = Harness::new();
+    // only the instance topic is read, a publish to the shared topic fails
+    harness
+        .consume(Source::Instance("test".to_string()), 3)
+        .await;
+    let request = request();
+    harness.announce(&request, "test");
+    let (_, mut received) = harness.connect(&request, None, true).await;
+
+    harness.send(&request).await;
+
+    let message = next_message(&mut received).await;
+    assert!(message.message_id.is_some());
+}
@@ -0,0 +1,23 @@
+use super::{next_message, request, Harness};
+use crate::errors::GenericError;
+
+#[actix_web::test]
+async fn send_without_a_consumer_fails() {
+    let harness = Harness::new();
+
+    let result = harness.try_send(&request()).await;
+
+    assert!(matches!(result, Err(GenericError::UnexpectedError(_))));
+}
+
+#[actix_web::test]
+async fn send_reaches_a_connected_key_through_the_shared_topic() {
+    let harness = Harness::start(3).await;
+    let request = request();
+    let (_, mut received) = harness.connect(&request, None, true).await;
+
+    harness.send(&request).await;
+
+    let message = next_message(&mut received).await;
+    assert!(message.message_id.is_some());
+}
@@ -0,0 +1,28 @@
+use super::{next_message, request, Harness};
+
+#[actix_web::test]
+async fn every_socket_of_a_key_receives_the_message() {
+    let harness = Harness::start(3).await;
+    let request = request();
+    let (_, mut first) = harness.connect(&request, None, true).await;
+    let (_, mut second) = harness.connect(&request, None, true).await;
+
+    harness.send(&request).await;
+
+    let message_id = next_message(&mut first).await.message_id;
+    assert!(message_id.is_some());
+    assert_eq!(next_message(&mut second).await.message_id, message_id);
+}
+
+#[actix_web::test]
+async fn disconnecting_one_socket_keeps_the_other() {
+    let harness = Harness::start(3).await;
+    let request = request();
+    let (first, _) = harness.connect(&request, None, true).await;
+    let (_, mut second) = harness.connect(&request, None, true).await;
+
+    harness.disconnect(&request, first).await;
+    harness.send(&request).await;
+
+    assert!(next_message(&mut second).await.message_id.is_some());
+}
@@ -0,0 +1,93 @@
+use super::{authorization, request, session_query, Harness, ACK_TIMEOUT, WAIT};
+use crate::schemas::{WSRequest, ADMIN_SCOPE};
+use crate::websocket::PayloadMeta;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::test::TestRequest;
+use serde_json::json;
+use std::pin::Pin;
+
+/// Opens the event stream of the key of `request`.
+async fn open(harness: &Harness, request: &WSRequest) -> BoxBody {
+    let response = harness
+        .call(TestRequest::get().uri(&format!("/events?{}", session_query(request))))
+        .await;
+    assert!(response.status().is_success());
+    response.into_body()
+}
+
+/// Next event of the stream, `None` once it ended.
+async fn next_event(body: &mut BoxBody) -> Option<String> {
+    let chunk = tokio::time::timeout(
+        WAIT,
+        futures::future::poll_fn(|cx| Pin::new(&mut *body).poll_next(cx)),
+    )
+    .await
+    .expect("no event in time")?;
+    Some(String::from_utf8(chunk.unwrap().to_vec()).unwrap())
+}
+
+#[actix_web::test]
+async fn event_stream_receives_messages_of_the_key() {
+    let harness = Harness::start(3).await;
+    let request = request();
+    let mut body = open(&harness, &request).await;
+
+    harness.send(&request).await;
+
+    let event = next_event(&mut body).await.unwrap();
+    let data = event
+        .lines()
+        .find_map(|line| line.strip_prefix("data: "))
+        .unwrap();
+    let sequence = PayloadMeta::parse(data).sequence.unwrap();
+    assert!(event.starts_with(&format!("id: {}\n", sequence)));
+    // acked once written, so not redelivered
+    let redelivered = tokio::time::timeout(
+        ACK_TIMEOUT * 5,
+        futures::future::poll_fn(|cx| Pin::new(&mut body).poll_next(cx)),
+    )
+    .await;
+    assert!(redelivered.is_err());
+}
+
+#[actix_web::test]
+async fn event_stream_without_a_token_is_refused() {
+    let harness = Harness::new();
+
+    let request = request();
+    let uri = format!(
+        "/events?user_id={}&business_id={}&device_id=device",
+        request.user_id.unwrap(),
+        request.business_id.unwrap()
+    );
+
+    let response = harness.call(TestRequest::get().uri(&uri)).await;
+
+    assert_eq!(response.status(), 400);
+}
+
+#[actix_web::test]
+async fn kicked_event_stream_ends_with_a_close_event() {
+    let harness = Harness::new();
+    let request = request();
+    let mut body = open(&harness, &request).await;
+    harness.wait_for_session(&request).await;
+
+    harness
+        .call(
+            TestRequest::post()
+                .uri("/admin/sessions/kick")
+                .insert_header(authorization(&[ADMIN_SCOPE]))
+                .set_json(json!({
+                    "user_id": request.user_id,
+                    "business_id": request.business_id,
+                    "device_id": "device",
+                    "code": 4001,
+                })),
+        )
+        .await;
+
+    let event = next_event(&mut body).await.unwrap();
+    assert!(event.starts_with("event: close\n"));
+    assert_eq!(next_event(&mut body).await, None);
+}
@@ -0,0 +1,59 @@
+use super::{assert_quiet, next_message, request, Harness};
+use crate::schemas::{TargetType, WSRequest};
+use uuid::Uuid;
+
+fn device_of(user_id: Uuid, business_id: Uuid) -> WSRequest {
+    let mut device = request();
+    device.user_id = Some(user_id);
+    device.business_id = Some(business_id);
+    device
+}
+
+#[actix_web::test]
+async fn business_target_reaches_every_device_of_the_business() {
+    let harness = Harness::start(3).await;
+    let mut request = request();
+    let business_id = request.business_id.unwrap();
+    let (_, mut first) = harness.connect(&request, None, true).await;
+    let colleague = device_of(Uuid::new_v4(), business_id);
+    let (_, mut second) = harness.connect(&colleague, None, true).await;
+    let other = device_of(request.user_id.unwrap(), Uuid::new_v4());
+    let (_, mut elsewhere) = harness.connect(&other, None, true).await;
+
+    request.target = TargetType::Business;
+    harness.send(&request).await;
+
+    let message_id = next_message(&mut first).await.message_id;
+    assert_eq!(next_message(&mut second).await.message_id, message_id);
+    assert_quiet(&mut elsewhere).await;
+}
+
+#[actix_web::test]
+async fn user_business_target_leaves_out_other_businesses_of_the_user() {
+    let harness = Harness::start(3).await;
+    let mut request = request();
+    let (_, mut received) = harness.connect(&request, None, true).await;
+    let other = device_of(request.user_id.unwrap(), Uuid::new_v4());
+    let (_, mut elsewhere) = harness.connect(&other, None, true).await;
+
+    request.target = TargetType::UserBusiness;
+    harness.send(&request).await;
+
+    assert!(next_message(&mut received).await.message_id.is_some());
+    assert_quiet(&mut elsewhere).await;
+}
+
+#[actix_web::test]
+async fn user_target_reaches_the_user_in_every_business() {
+    let harness = Harness::start(3).await;
+    let mut request = request();
+    let (_, mut first) = harness.connect(&request, None, true).await;
+    let other = device_of(request.user_id.unwrap(), Uuid::new_v4());
+    let (_, mut second) = harness.connect(&other, None, true).await;
+
+    request.target = TargetType::User;
+    harness.send(&request).await;
+
+    let message_id = next_message(&mut first).await.message_id;
+    assert_eq!(next_message(&mut second).await.message_id, message_id);
+}
@@ -0,0 +1,85 @@
+use super::{assert_quiet, request, Harness, WAIT};
+use crate::errors::GenericError;
+use crate::schemas::{TargetType, WSKeyTrait, WSRequest};
+use crate::websocket::SubscribeTransaction;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// A callback of the transaction for the business of `subscriber`.
+fn callback(subscriber: &WSRequest, transaction_id: &str) -> WSRequest {
+    let mut callback = request();
+    callback.business_id = subscriber.business_id;
+    callback.target = TargetType::Transaction;
+    callback.data = json!({
+        "context": {"transaction_id": transaction_id, "message_id": "ondc-message"},
+        "message": {},
+    });
+    callback
+}
+
+async fn subscribe(harness: &Harness, request: &WSRequest, transaction_id: &str, subscribe: bool) {
+    harness
+        .server
+        .send(SubscribeTransaction {
+            id: request.get_ws_key(),
+            business_id: request.business_id,
+            transaction_id: transaction_id.to_string(),
+            subscribe,
+        })
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+async fn next_payload(receiver: &mut mpsc::UnboundedReceiver<String>) -> Value {
+    let payload = tokio::time::timeout(WAIT, receiver.recv())
+        .await
+        .expect("no message in time")
+        .unwrap();
+    serde_json::from_str(&payload).unwrap()
+}
+
+#[actix_web::test]
+async fn callback_reaches_the_subscribed_sessions_of_the_business() {
+    let harness = Harness::start(3).await;
+    let transaction_id = Uuid::new_v4().to_string();
+    let (subscribed, other_business) = (request(), request());
+    let (_, mut received) = harness.connect(&subscribed, None, true).await;
+    let (_, mut elsewhere) = harness.connect(&other_business, None, true).await;
+    subscribe(&harness, &subscribed, &transaction_id, true).await;
+    subscribe(&harness, &other_business, &transaction_id, true).await;
+
+    harness.send(&callback(&subscribed, &transaction_id)).await;
+
+    let payload = next_payload(&mut received).await;
+    assert_eq!(payload["transactionId"], json!(transaction_id));
+    assert_eq!(payload["ondcMessageId"], "ondc-message");
+    assert_quiet(&mut elsewhere).await;
+}
+
+#[actix_web::test]
+async fn callback_of_an_unfollowed_transaction_is_dropped() {
+    let harness = Harness::start(3).await;
+    let transaction_id = Uuid::new_v4().to_string();
+    let request = request();
+    let (_, mut received) = harness.connect(&request, None, true).await;
+    subscribe(&harness, &request, &transaction_id, true).await;
+    subscribe(&harness, &request, &transaction_id, false).await;
+
+    harness.send(&callback(&request, &transaction_id)).await;
+
+    assert_quiet(&mut received).await;
+    assert!(harness.broker.dead_letters().is_empty());
+}
+
+#[actix_web::test]
+async fn callback_without_a_business_is_invalid() {
+    let harness = Harness::start(3).await;
+    let mut callback = callback(&request(), "transaction");
+    callback.business_id = None;
+
+    let result = harness.try_send(&callback).await;
+
+    assert!(matches!(result, Err(GenericError::ValidationError(_))));
+}
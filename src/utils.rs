@@ -9,14 +9,18 @@ use secrecy::{ExposeSecret, SecretString};
 use jsonwebtoken::{
     decode, encode, Algorithm as JWTAlgorithm, DecodingKey, EncodingKey, Header, Validation,
 };
+use actix_web::HttpRequest;
+use uuid::Uuid;
 
+const WS_TOKEN_PROTOCOL: &str = "access_token";
 
 
-#[tracing::instrument(name = "Decode JWT token")]
+
+#[tracing::instrument(name = "Decode JWT token", skip(token))]
 pub fn decode_token<T: Into<String> + std::fmt::Debug>(
     token: T,
     secret: &SecretString,
-) -> Result<String, CustomJWTTokenError> {
+) -> Result<JWTClaims, CustomJWTTokenError> {
     let decoding_key = DecodingKey::from_secret(secret.expose_secret().as_bytes());
     let decoded = decode::<JWTClaims>(
         &token.into(),
@@ -24,7 +28,7 @@ pub fn decode_token<T: Into<String> + std::fmt::Debug>(
         &Validation::new(JWTAlgorithm::HS256),
     );
     match decoded {
-        Ok(token) => Ok(token.claims.sub.to_string()),
+        Ok(token) => Ok(token.claims),
         Err(e) => {
             match e.kind() {
                 jsonwebtoken::errors::ErrorKind::ExpiredSignature => {
@@ -36,6 +40,45 @@ pub fn decode_token<T: Into<String> + std::fmt::Debug>(
     }
 }
 
+/// Looks up the WebSocket token in the query string, then the `Sec-WebSocket-Protocol`
+/// header (`access_token, <token>`), then the `token` cookie.
+pub fn get_websocket_token(req: &HttpRequest, query_token: Option<&str>) -> Option<String> {
+    if let Some(token) = query_token {
+        return Some(token.to_string());
+    }
+    let protocol_token = req
+        .headers()
+        .get(actix_web::http::header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|protocols| {
+            let mut protocols = protocols.split(',').map(str::trim);
+            protocols
+                .by_ref()
+                .find(|p| *p == WS_TOKEN_PROTOCOL)
+                .and_then(|_| protocols.next())
+                .map(str::to_string)
+        });
+    protocol_token.or_else(|| req.cookie("token").map(|c| c.value().to_string()))
+}
+
+/// Identifies this replica for cross-instance routing: the configured id, else the hostname.
+pub fn get_instance_id(configured: Option<&str>) -> String {
+    configured
+        .map(str::to_string)
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .or_else(|| {
+            std::fs::read_to_string("/etc/hostname")
+                .ok()
+                .map(|h| h.trim().to_string())
+        })
+        .filter(|id| !id.is_empty())
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+pub fn websocket_protocols() -> [&'static str; 1] {
+    [WS_TOKEN_PROTOCOL]
+}
+
 pub fn error_chain_fmt(
     e: &impl std::error::Error,
     f: &mut std::fmt::Formatter<'_>,
@@ -71,6 +114,8 @@ pub fn get_configuration() -> Result<Settings, ConfigError> {
 #[tracing::instrument(name = "Generate JWT token for user")]
 pub fn generate_jwt_token_for_user(
     user_id: &str,
+    business_id: Option<Uuid>,
+    scopes: Vec<String>,
     expiry_time: i64,
     secret: &SecretString,
 ) -> Result<SecretString, anyhow::Error> {
@@ -81,6 +126,8 @@ pub fn generate_jwt_token_for_user(
     let claims: JWTClaims = JWTClaims {
         sub: user_id.to_owned(),
         exp: expiration,
+        business_id,
+        scopes,
     };
     let header = Header::new(JWTAlgorithm::HS256);
     let encoding_key = EncodingKey::from_secret(secret.expose_secret().as_bytes());
@@ -1,23 +1,37 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+use crate::broker::{InboundPublisher, MessageData};
+use crate::buffer::{BufferWorker, BufferedMessage, MemoryBuffer, MessageBuffer, Stored};
+use crate::presence::PresenceNotifier;
+use crate::schemas::{WSKeyTrait, WebSocketParam};
 
 use actix::prelude::{Actor, Context, Handler, Message as ActixMessage, Recipient};
+use actix::MessageResponse;
+use actix_web::http::header::USER_AGENT;
+use actix_web::HttpRequest;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use serde_json::{error::Result as SerdeResult, to_string, Value};
-use std::time::{Duration, Instant};
+use serde_json::{to_string, Value};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
 use tracing::{error, info, warn};
 use utoipa::ToSchema;
 
 use actix::{
     fut,
     prelude::{Addr, StreamHandler},
-    ActorContext, ActorFutureExt, AsyncContext, ContextFutureSpawner, WrapFuture,
+    ActorContext, ActorFutureExt, AsyncContext, ContextFutureSpawner, Running, WrapFuture,
 };
 use actix_web_actors::ws;
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(15);
+/// Most transactions a key can be subscribed to at once.
+const MAX_TRANSACTIONS_PER_KEY: usize = 100;
 
-#[derive(Debug, Serialize, ToSchema, Deserialize)]
+#[derive(Debug, Serialize, ToSchema, Deserialize, Clone, Copy, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum WebSocketActionType {
     Search,
@@ -32,79 +46,509 @@ pub enum WebSocketActionType {
     IssueStatus,
 }
 
+impl WebSocketActionType {
+    /// Actions a client may initiate over the socket, `Info` is server to client only.
+    pub fn is_inbound(&self) -> bool {
+        !matches!(self, WebSocketActionType::Info)
+    }
+}
+
 #[derive(ActixMessage)]
 #[rtype(result = "()")]
 pub struct Message(pub String);
 
+/// Fields of a `Message` payload that transports without client acks need.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PayloadMeta {
+    pub message_id: Option<Uuid>,
+    pub sequence: Option<u64>,
+}
+
+impl PayloadMeta {
+    pub fn parse(payload: &str) -> Self {
+        serde_json::from_str(payload).unwrap_or_default()
+    }
+}
+
+/// Group of sessions a message fans out to when it has no exact key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum MessageTarget {
+    #[serde(rename_all = "camelCase")]
+    Business { business_id: Uuid },
+    #[serde(rename_all = "camelCase")]
+    User { user_id: Uuid },
+    #[serde(rename_all = "camelCase")]
+    UserBusiness { user_id: Uuid, business_id: Uuid },
+    /// Sessions of the business subscribed to the ONDC transaction
+    #[serde(rename_all = "camelCase")]
+    Transaction {
+        transaction_id: String,
+        business_id: Uuid,
+    },
+}
+
 #[derive(ActixMessage, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 #[rtype(result = "()")]
 pub struct MessageToClient {
+    /// Exact WebSocket key, the message is broadcast to everyone when neither this nor `target` is set.
     pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<MessageTarget>,
+    #[serde(default = "Uuid::new_v4")]
+    pub message_id: Uuid,
+    /// Per-key sequence number, assigned by `Server` when the message is dispatched.
+    #[serde(default)]
+    pub sequence: u64,
+    /// `context.transaction_id` of the ONDC payload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transaction_id: Option<String>,
+    /// `context.message_id` of the ONDC payload, unrelated to `message_id`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ondc_message_id: Option<String>,
     pub action_type: WebSocketActionType,
     pub data: Value,
 }
 
 impl MessageToClient {
     pub fn new(msg_type: WebSocketActionType, data: Value, id: Option<String>) -> Self {
+        let context = |field: &str| {
+            data.get("context")
+                .and_then(|context| context.get(field))
+                .and_then(Value::as_str)
+                .map(str::to_string)
+        };
         Self {
             id,
+            target: None,
+            message_id: Uuid::new_v4(),
+            sequence: 0,
+            transaction_id: context("transaction_id"),
+            ondc_message_id: context("message_id"),
             action_type: msg_type,
             data,
         }
     }
+
+    pub fn with_target(mut self, target: MessageTarget) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        self.id.is_none() && self.target.is_none()
+    }
+
+    /// Key used for broker ordering, messages for the same recipients stay in order.
+    pub fn partition_key(&self) -> String {
+        match (&self.target, &self.id) {
+            (Some(MessageTarget::Business { business_id }), _) => format!("NA#{}#NA", business_id),
+            (Some(MessageTarget::User { user_id }), _) => format!("{}#NA#NA", user_id),
+            (
+                Some(MessageTarget::UserBusiness {
+                    user_id,
+                    business_id,
+                }),
+                _,
+            ) => format!("{}#{}#NA", user_id, business_id),
+            (Some(MessageTarget::Transaction { transaction_id, .. }), _) => {
+                format!("transaction#{}", transaction_id)
+            }
+            (None, Some(id)) => id.clone(),
+            (None, None) => "broadcast".to_string(),
+        }
+    }
+}
+
+/// Frames a client can send over the socket.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    #[serde(rename_all = "camelCase")]
+    Ack { message_id: Uuid },
+    #[serde(rename_all = "camelCase")]
+    Action {
+        action_type: WebSocketActionType,
+        data: Value,
+        correlation_id: Option<Uuid>,
+    },
+    /// Receive messages sent to the `Transaction` target of the ONDC transaction
+    #[serde(rename_all = "camelCase")]
+    Subscribe { transaction_id: String },
+    #[serde(rename_all = "camelCase")]
+    Unsubscribe { transaction_id: String },
+}
+
+/// Control frames sent in reply to client frames.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    #[serde(rename_all = "camelCase")]
+    ActionAccepted { correlation_id: Uuid },
+    /// Part of a message larger than the frame limit, the `data` of all chunks of a `chunk_id`
+    /// joined in `index` order is the message
+    #[serde(rename_all = "camelCase")]
+    Chunk {
+        chunk_id: Uuid,
+        message_id: Option<Uuid>,
+        index: usize,
+        total: usize,
+        data: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Subscribed { transaction_id: String },
+    #[serde(rename_all = "camelCase")]
+    Unsubscribed { transaction_id: String },
+    #[serde(rename_all = "camelCase")]
+    Error {
+        correlation_id: Option<Uuid>,
+        message: String,
+    },
+}
+
+/// A client action as published on the inbound topic.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct InboundMessage {
+    pub ws_key: String,
+    pub correlation_id: Uuid,
+    pub action_type: WebSocketActionType,
+    pub data: Value,
+}
+
+struct PendingAck {
+    token: u64,
+    sender: oneshot::Sender<()>,
+    /// Connections the message was sent to, the only ones whose ack counts
+    receivers: HashSet<Uuid>,
 }
 
 pub struct Server {
-    sessions: HashMap<String, Recipient<Message>>,
+    sessions: HashMap<String, HashMap<Uuid, Recipient<Message>>>,
+    by_business: HashMap<Uuid, HashSet<String>>,
+    by_user: HashMap<Uuid, HashSet<String>>,
+    by_user_business: HashMap<(Uuid, Uuid), HashSet<String>>,
+    by_transaction: HashMap<String, HashSet<String>>,
+    /// Transactions each key is subscribed to
+    transactions: HashMap<String, HashSet<String>>,
+    /// Last sequence handed out, for any key
+    last_sequence: u64,
+    pending_acks: HashMap<Uuid, PendingAck>,
+    next_ack_token: u64,
+    ack_timeout: Duration,
+    buffer: BufferWorker,
+    buffer_ttl: Duration,
+    /// Messages for connections whose buffered messages are still being read, by connection id
+    replaying: HashMap<Uuid, Vec<String>>,
+    presence: Option<PresenceNotifier>,
+    /// by connection id
+    details: HashMap<Uuid, SessionInfo>,
+    /// by connection id
+    closers: HashMap<Uuid, Recipient<CloseSession>>,
 }
 
 impl Default for Server {
     fn default() -> Self {
-        Self::new()
+        Self::new(Box::new(MemoryBuffer::default()), Duration::from_secs(3600))
     }
 }
 
 impl Server {
-    pub fn new() -> Self {
+    pub fn new(buffer: Box<dyn MessageBuffer>, buffer_ttl: Duration) -> Self {
         Self {
             sessions: HashMap::new(),
+            by_business: HashMap::new(),
+            by_user: HashMap::new(),
+            by_user_business: HashMap::new(),
+            by_transaction: HashMap::new(),
+            transactions: HashMap::new(),
+            last_sequence: 0,
+            pending_acks: HashMap::new(),
+            next_ack_token: 0,
+            ack_timeout: ACK_TIMEOUT,
+            buffer: BufferWorker::start(buffer),
+            buffer_ttl,
+            replaying: HashMap::new(),
+            presence: None,
+            details: HashMap::new(),
+            closers: HashMap::new(),
         }
     }
+
+    /// Announces keys connecting to and leaving this instance for cross-instance routing.
+    pub fn with_presence(mut self, presence: PresenceNotifier) -> Self {
+        self.presence = Some(presence);
+        self
+    }
+
+    /// How long a delivered message waits for a client ack, `ACK_TIMEOUT` by default.
+    pub fn with_ack_timeout(mut self, ack_timeout: Duration) -> Self {
+        self.ack_timeout = ack_timeout;
+        self
+    }
     pub fn session_exists(&self, id: &str) -> bool {
         self.sessions.contains_key(id)
     }
 
-    fn send_message_to(&self, id: &str, data: SerdeResult<String>) {
-        if let Some(recipient) = self.sessions.get(id) {
-            match data {
-                Ok(data) => {
-                    if let Err(err) = recipient.try_send(Message(data)) {
-                        error!("Error sending client message: {:?}", err);
-                    }
+    /// Sequences are the time in microseconds, bumped when several fall in the same one, so they
+    /// keep increasing for a key across restarts and instances without storing any per key.
+    fn next_sequence(&mut self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or_default();
+        self.last_sequence = now.max(self.last_sequence + 1);
+        self.last_sequence
+    }
+
+    /// Sends the message to every connection of the key, returns the number of connections reached.
+    /// Messages for a key without sessions are buffered until it connects when `buffer` is set.
+    fn send_message_to(
+        &mut self,
+        id: &str,
+        msg: &mut MessageToClient,
+        buffer: bool,
+    ) -> DispatchResult {
+        msg.sequence = self.next_sequence();
+        let data = match to_string(&msg) {
+            Ok(data) => data,
+            Err(err) => {
+                error!("Data did not convert to string {:?}", err);
+                return DispatchResult::Failed;
+            }
+        };
+        if !self.sessions.contains_key(id) && !buffer {
+            return DispatchResult::Failed;
+        }
+        if !self.sessions.contains_key(id) {
+            warn!("No session found with ID: {}, buffering message", id);
+            let buffered = BufferedMessage::new(msg.sequence, data, self.buffer_ttl);
+            return DispatchResult::Buffered(vec![self.buffer.push(id, buffered)]);
+        }
+        let mut receivers = HashSet::new();
+        for (conn_id, recipient) in &self.sessions[id] {
+            // kept until the buffered messages of the connection went out, so they stay in order
+            let result = match self.replaying.get_mut(conn_id) {
+                Some(queued) => {
+                    queued.push(data.clone());
+                    Ok(())
                 }
-                Err(err) => {
-                    error!("Data did not convert to string {:?}", err);
+                None => recipient.try_send(Message(data.clone())),
+            };
+            match result {
+                Ok(_) => {
+                    receivers.insert(*conn_id);
+                    if let Some(details) = self.details.get_mut(conn_id) {
+                        details.messages_sent += 1;
+                    }
                 }
+                Err(err) => error!("Error sending client message: {:?}", err),
             }
+        }
+        if receivers.is_empty() {
+            DispatchResult::Failed
         } else {
-            warn!("No session found with ID: {}", id);
+            DispatchResult::Sent(receivers)
         }
     }
 
-    fn send_message_to_all(&self, data: SerdeResult<String>) {
-        match data {
-            Ok(data) => {
-                for recipient in self.sessions.values() {
-                    if let Err(err) = recipient.try_send(Message(data.clone())) {
-                        error!("Error sending client message: {:?}", err);
-                    }
+    fn send_message_to_all(&mut self, msg: &mut MessageToClient) -> DispatchResult {
+        let ids: Vec<String> = self.sessions.keys().cloned().collect();
+        ids.iter()
+            .map(|id| self.send_message_to(id, msg, true))
+            .fold(DispatchResult::Failed, DispatchResult::merge)
+    }
+
+    fn target_ids(&self, target: &MessageTarget) -> Vec<String> {
+        if let MessageTarget::Transaction {
+            transaction_id,
+            business_id,
+        } = target
+        {
+            // subscriptions aren't checked against the transaction, the business of the
+            // session is what keeps callbacks from reaching other businesses
+            let of_business = |id: &String| {
+                self.by_business
+                    .get(business_id)
+                    .is_some_and(|ids| ids.contains(id))
+            };
+            return self
+                .by_transaction
+                .get(transaction_id)
+                .into_iter()
+                .flatten()
+                .filter(|id| of_business(id))
+                .cloned()
+                .collect();
+        }
+        let ids = match target {
+            MessageTarget::Business { business_id } => self.by_business.get(business_id),
+            MessageTarget::User { user_id } => self.by_user.get(user_id),
+            MessageTarget::UserBusiness {
+                user_id,
+                business_id,
+            } => self.by_user_business.get(&(*user_id, *business_id)),
+            MessageTarget::Transaction { .. } => None,
+        };
+        ids.into_iter().flatten().cloned().collect()
+    }
+
+    fn send_message_to_target(
+        &mut self,
+        target: &MessageTarget,
+        msg: &mut MessageToClient,
+        buffer: bool,
+    ) -> DispatchResult {
+        let ids = self.target_ids(target);
+        if ids.is_empty() && matches!(target, MessageTarget::Transaction { .. }) {
+            return DispatchResult::Unsubscribed;
+        }
+        ids.iter()
+            .map(|id| self.send_message_to(id, msg, buffer))
+            .fold(DispatchResult::Failed, DispatchResult::merge)
+    }
+
+    fn dispatch(&mut self, mut msg: MessageToClient, buffer: bool) -> DispatchResult {
+        match (msg.target.clone(), msg.id.clone()) {
+            (Some(target), _) => self.send_message_to_target(&target, &mut msg, buffer),
+            (None, Some(id)) => self.send_message_to(&id, &mut msg, buffer),
+            (None, None) => self.send_message_to_all(&mut msg),
+        }
+    }
+
+    fn index_session(&mut self, id: &str, user_id: Option<Uuid>, business_id: Option<Uuid>) {
+        if let Some(business_id) = business_id {
+            self.by_business
+                .entry(business_id)
+                .or_default()
+                .insert(id.to_string());
+        }
+        if let Some(user_id) = user_id {
+            self.by_user
+                .entry(user_id)
+                .or_default()
+                .insert(id.to_string());
+        }
+        if let (Some(user_id), Some(business_id)) = (user_id, business_id) {
+            self.by_user_business
+                .entry((user_id, business_id))
+                .or_default()
+                .insert(id.to_string());
+        }
+    }
+
+    fn unindex_session(&mut self, id: &str, user_id: Option<Uuid>, business_id: Option<Uuid>) {
+        fn remove<K: std::hash::Hash + Eq>(
+            index: &mut HashMap<K, HashSet<String>>,
+            k: K,
+            id: &str,
+        ) {
+            if let Some(ids) = index.get_mut(&k) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    index.remove(&k);
                 }
             }
-            Err(err) => {
-                error!("Data did not convert to string {:?}", err);
+        }
+        if let Some(business_id) = business_id {
+            remove(&mut self.by_business, business_id, id);
+        }
+        if let Some(user_id) = user_id {
+            remove(&mut self.by_user, user_id, id);
+        }
+        if let (Some(user_id), Some(business_id)) = (user_id, business_id) {
+            remove(&mut self.by_user_business, (user_id, business_id), id);
+        }
+    }
+
+    fn subscribe(&mut self, id: &str, transaction_id: &str) -> Result<(), String> {
+        let transactions = self.transactions.entry(id.to_string()).or_default();
+        if transactions.len() >= MAX_TRANSACTIONS_PER_KEY && !transactions.contains(transaction_id)
+        {
+            return Err(format!(
+                "At most {} transactions can be subscribed to",
+                MAX_TRANSACTIONS_PER_KEY
+            ));
+        }
+        transactions.insert(transaction_id.to_string());
+        self.by_transaction
+            .entry(transaction_id.to_string())
+            .or_default()
+            .insert(id.to_string());
+        Ok(())
+    }
+
+    fn unsubscribe(&mut self, id: &str, transaction_id: &str) {
+        if let Some(transactions) = self.transactions.get_mut(id) {
+            transactions.remove(transaction_id);
+            if transactions.is_empty() {
+                self.transactions.remove(id);
             }
         }
+        if let Some(ids) = self.by_transaction.get_mut(transaction_id) {
+            ids.remove(id);
+            if ids.is_empty() {
+                self.by_transaction.remove(transaction_id);
+            }
+        }
+    }
+
+    /// Reads the buffered messages of the key off the actor thread, messages for the connection
+    /// are held back until they are sent through `Replayed`.
+    fn flush_buffer(
+        &mut self,
+        id: &str,
+        conn_id: Uuid,
+        last_seen: Option<u64>,
+        ctx: &mut Context<Self>,
+    ) {
+        let addr = ctx.address();
+        let key = id.to_string();
+        let result = self.buffer.drain(id, last_seen, move |messages| {
+            addr.do_send(Replayed {
+                id: key,
+                conn_id,
+                messages,
+            })
+        });
+        match result {
+            Ok(_) => {
+                self.replaying.insert(conn_id, vec![]);
+            }
+            Err(err) => error!("Failed to read buffered messages: {:?}", err),
+        }
+    }
+}
+
+enum DispatchResult {
+    /// To the given connections
+    Sent(HashSet<Uuid>),
+    /// Resolving once each buffered key stored it
+    Buffered(Vec<Stored>),
+    /// No session is subscribed to the transaction of a `Transaction` target
+    Unsubscribed,
+    Failed,
+}
+
+impl DispatchResult {
+    /// Combines fan-out results, a message counts as sent if any session received it.
+    fn merge(self, other: Self) -> Self {
+        match (self, other) {
+            (Self::Sent(mut receivers), Self::Sent(other)) => {
+                receivers.extend(other);
+                Self::Sent(receivers)
+            }
+            (Self::Sent(receivers), _) | (_, Self::Sent(receivers)) => Self::Sent(receivers),
+            (Self::Buffered(mut stored), Self::Buffered(other)) => {
+                stored.extend(other);
+                Self::Buffered(stored)
+            }
+            (Self::Buffered(stored), _) | (_, Self::Buffered(stored)) => Self::Buffered(stored),
+            (Self::Unsubscribed, _) | (_, Self::Unsubscribed) => Self::Unsubscribed,
+            _ => Self::Failed,
+        }
     }
 }
 
@@ -117,27 +561,109 @@ impl Actor for Server {
 pub struct Connect {
     pub addr: Recipient<Message>,
     pub id: String,
+    pub conn_id: Uuid,
+    pub user_id: Option<Uuid>,
+    pub business_id: Option<Uuid>,
+    pub last_seen: Option<u64>,
+    pub client: ClientInfo,
+    pub closer: Recipient<CloseSession>,
 }
 
 impl Handler<Connect> for Server {
     type Result = ();
 
-    fn handle(&mut self, msg: Connect, _: &mut Context<Self>) {
-        self.sessions.insert(msg.id.clone(), msg.addr);
+    fn handle(&mut self, msg: Connect, ctx: &mut Context<Self>) {
+        let now = Utc::now();
+        self.details.insert(
+            msg.conn_id,
+            SessionInfo {
+                conn_id: msg.conn_id,
+                ws_key: msg.id.clone(),
+                user_id: msg.user_id,
+                business_id: msg.business_id,
+                connected_at: now,
+                last_heartbeat: now,
+                remote_addr: msg.client.remote_addr,
+                user_agent: msg.client.user_agent,
+                messages_sent: 0,
+                messages_received: 0,
+            },
+        );
+        self.closers.insert(msg.conn_id, msg.closer);
+        self.flush_buffer(&msg.id, msg.conn_id, msg.last_seen, ctx);
+        self.index_session(&msg.id, msg.user_id, msg.business_id);
+        if let (Some(presence), false) = (&self.presence, self.sessions.contains_key(&msg.id)) {
+            presence.notify(&msg.id, true);
+        }
+        self.sessions
+            .entry(msg.id)
+            .or_default()
+            .insert(msg.conn_id, msg.addr);
     }
 }
 
+/// Removes a single connection; the key stays registered while other sockets for it are alive.
 #[derive(ActixMessage)]
 #[rtype(result = "()")]
 pub struct Disconnect {
     pub id: String,
+    pub conn_id: Uuid,
+    pub user_id: Option<Uuid>,
+    pub business_id: Option<Uuid>,
 }
 
 impl Handler<Disconnect> for Server {
     type Result = ();
 
     fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
-        self.sessions.remove(&msg.id);
+        self.details.remove(&msg.conn_id);
+        self.replaying.remove(&msg.conn_id);
+        self.closers.remove(&msg.conn_id);
+        if let Some(connections) = self.sessions.get_mut(&msg.id) {
+            connections.remove(&msg.conn_id);
+            if connections.is_empty() {
+                self.sessions.remove(&msg.id);
+                self.unindex_session(&msg.id, msg.user_id, msg.business_id);
+                for transaction_id in self.transactions.remove(&msg.id).into_iter().flatten() {
+                    self.unsubscribe(&msg.id, &transaction_id);
+                }
+                if let Some(presence) = &self.presence {
+                    presence.notify(&msg.id, false);
+                }
+            }
+        }
+    }
+}
+
+/// Buffered messages of a connecting key, sent before the messages that arrived meanwhile.
+#[derive(ActixMessage)]
+#[rtype(result = "()")]
+struct Replayed {
+    id: String,
+    conn_id: Uuid,
+    messages: Vec<BufferedMessage>,
+}
+
+impl Handler<Replayed> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: Replayed, _: &mut Context<Self>) {
+        let Some(queued) = self.replaying.remove(&msg.conn_id) else {
+            return;
+        };
+        let Some(recipient) = self
+            .sessions
+            .get(&msg.id)
+            .and_then(|connections| connections.get(&msg.conn_id))
+        else {
+            return;
+        };
+        let payloads = msg.messages.into_iter().map(|buffered| buffered.payload);
+        for payload in payloads.chain(queued) {
+            if let Err(err) = recipient.try_send(Message(payload)) {
+                error!("Error sending buffered message: {:?}", err);
+            }
+        }
     }
 }
 
@@ -155,40 +681,494 @@ impl Handler<SessionExists> for Server {
     }
 }
 
+/// Where a connection comes from, reported by the admin session listing.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub remote_addr: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        Self {
+            remote_addr: req
+                .connection_info()
+                .realip_remote_addr()
+                .map(str::to_string),
+            user_agent: req
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+        }
+    }
+}
+
+/// A single connection as seen by the admin API.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SessionInfo {
+    #[schema(value_type = String)]
+    pub conn_id: Uuid,
+    pub ws_key: String,
+    #[schema(value_type = String)]
+    pub user_id: Option<Uuid>,
+    #[schema(value_type = String)]
+    pub business_id: Option<Uuid>,
+    pub connected_at: DateTime<Utc>,
+    pub last_heartbeat: DateTime<Utc>,
+    pub remote_addr: Option<String>,
+    pub user_agent: Option<String>,
+    /// Messages pushed to the client
+    pub messages_sent: u64,
+    /// Frames received from the client
+    pub messages_received: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Activity {
+    Heartbeat,
+    FrameReceived,
+}
+
+/// Reported by a session so the admin API shows its liveness and traffic.
+#[derive(ActixMessage)]
+#[rtype(result = "()")]
+pub struct SessionActivity {
+    pub conn_id: Uuid,
+    pub activity: Activity,
+}
+
+impl Handler<SessionActivity> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: SessionActivity, _: &mut Context<Self>) -> Self::Result {
+        if let Some(details) = self.details.get_mut(&msg.conn_id) {
+            match msg.activity {
+                Activity::Heartbeat => details.last_heartbeat = Utc::now(),
+                Activity::FrameReceived => details.messages_received += 1,
+            }
+        }
+    }
+}
+
+/// Connections of this instance, optionally only those of a business and/or user.
+#[derive(ActixMessage)]
+#[rtype(result = "Vec<SessionInfo>")]
+pub struct ListSessions {
+    pub business_id: Option<Uuid>,
+    pub user_id: Option<Uuid>,
+}
+
+impl Handler<ListSessions> for Server {
+    type Result = Vec<SessionInfo>;
+
+    fn handle(&mut self, msg: ListSessions, _: &mut Context<Self>) -> Self::Result {
+        let mut sessions: Vec<SessionInfo> = self
+            .details
+            .values()
+            .filter(|s| msg.business_id.is_none_or(|id| s.business_id == Some(id)))
+            .filter(|s| msg.user_id.is_none_or(|id| s.user_id == Some(id)))
+            .cloned()
+            .collect();
+        sessions.sort_by_key(|s| s.connected_at);
+        sessions
+    }
+}
+
+#[derive(ActixMessage)]
+#[rtype(result = "Option<SessionInfo>")]
+pub struct GetSession {
+    pub conn_id: Uuid,
+}
+
+impl Handler<GetSession> for Server {
+    type Result = Option<SessionInfo>;
+
+    fn handle(&mut self, msg: GetSession, _: &mut Context<Self>) -> Self::Result {
+        self.details.get(&msg.conn_id).cloned()
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BusinessSessionCount {
+    #[schema(value_type = String)]
+    pub business_id: Uuid,
+    pub sessions: usize,
+}
+
+/// Number of connections per business, connections without a business are left out.
+#[derive(ActixMessage)]
+#[rtype(result = "Vec<BusinessSessionCount>")]
+pub struct CountSessionsByBusiness;
+
+impl Handler<CountSessionsByBusiness> for Server {
+    type Result = Vec<BusinessSessionCount>;
+
+    fn handle(&mut self, _: CountSessionsByBusiness, _: &mut Context<Self>) -> Self::Result {
+        let mut counts: HashMap<Uuid, usize> = HashMap::new();
+        for business_id in self.details.values().filter_map(|s| s.business_id) {
+            *counts.entry(business_id).or_default() += 1;
+        }
+        counts
+            .into_iter()
+            .map(|(business_id, sessions)| BusinessSessionCount {
+                business_id,
+                sessions,
+            })
+            .collect()
+    }
+}
+
+/// Asks a session to send a close frame and stop.
+#[derive(ActixMessage, Clone)]
+#[rtype(result = "()")]
+pub struct CloseSession(pub ws::CloseReason);
+
+/// Closes the connections of a key or target group on this instance, returns how many were closed.
+#[derive(ActixMessage, Debug)]
+#[rtype(result = "usize")]
+pub struct KickSessions {
+    pub id: Option<String>,
+    pub target: Option<MessageTarget>,
+    pub reason: ws::CloseReason,
+}
+
+impl Handler<KickSessions> for Server {
+    type Result = usize;
+
+    fn handle(&mut self, msg: KickSessions, _: &mut Context<Self>) -> Self::Result {
+        let ids = match (&msg.target, msg.id) {
+            (Some(target), _) => self.target_ids(target),
+            (None, Some(id)) => vec![id],
+            (None, None) => vec![],
+        };
+        let mut closed = 0;
+        for conn_id in ids
+            .iter()
+            .filter_map(|id| self.sessions.get(id))
+            .flat_map(|connections| connections.keys())
+        {
+            // the session deregisters itself through `Disconnect` once stopped
+            if let Some(closer) = self.closers.get(conn_id) {
+                match closer.try_send(CloseSession(msg.reason.clone())) {
+                    Ok(_) => closed += 1,
+                    Err(err) => error!("Error closing session: {:?}", err),
+                }
+            }
+        }
+        closed
+    }
+}
+
 impl Handler<MessageToClient> for Server {
     type Result = ();
 
     fn handle(&mut self, msg: MessageToClient, _: &mut Context<Self>) -> Self::Result {
-        let message_str = to_string(&msg);
-        if let Some(id) = msg.id {
-            self.send_message_to(&id, message_str);
+        self.dispatch(msg, true);
+    }
+}
+
+#[derive(MessageResponse)]
+pub enum DeliveryStatus {
+    NoSession,
+    /// Held in the offline buffer until the key connects, once every store resolved.
+    Buffered(Vec<Stored>),
+    /// Nobody on this instance follows the transaction, the message is dropped.
+    Unsubscribed,
+    /// Resolves once a client acks the message, is cancelled after the ack timeout.
+    Sent(oneshot::Receiver<()>),
+}
+
+/// Dispatches a message and waits for a client `ack` frame.
+#[derive(ActixMessage)]
+#[rtype(result = "DeliveryStatus")]
+pub struct Deliver {
+    pub message: MessageToClient,
+    /// Off when other instances hold the key, the message is then theirs to deliver
+    pub buffer: bool,
+}
+
+impl Handler<Deliver> for Server {
+    type Result = DeliveryStatus;
+
+    fn handle(&mut self, msg: Deliver, ctx: &mut Context<Self>) -> Self::Result {
+        let message_id = msg.message.message_id;
+        let receivers = match self.dispatch(msg.message, msg.buffer) {
+            DispatchResult::Sent(receivers) => receivers,
+            DispatchResult::Buffered(stored) => return DeliveryStatus::Buffered(stored),
+            DispatchResult::Unsubscribed => return DeliveryStatus::Unsubscribed,
+            DispatchResult::Failed => return DeliveryStatus::NoSession,
+        };
+        let (sender, receiver) = oneshot::channel();
+        self.next_ack_token += 1;
+        let token = self.next_ack_token;
+        self.pending_acks.insert(
+            message_id,
+            PendingAck {
+                token,
+                sender,
+                receivers,
+            },
+        );
+        ctx.run_later(self.ack_timeout, move |act, _| {
+            if act
+                .pending_acks
+                .get(&message_id)
+                .is_some_and(|pending| pending.token == token)
+            {
+                warn!("No ack received for message: {}", message_id);
+                act.pending_acks.remove(&message_id);
+            }
+        });
+        DeliveryStatus::Sent(receiver)
+    }
+}
+
+#[derive(ActixMessage)]
+#[rtype(result = "()")]
+pub struct ClientAck {
+    /// Connection acking the message
+    pub conn_id: Uuid,
+    pub message_id: Uuid,
+}
+
+/// Subscribes or unsubscribes a key to the messages of an ONDC transaction.
+/// Subscriptions end when the last connection of the key leaves.
+#[derive(ActixMessage)]
+#[rtype(result = "Result<(), String>")]
+pub struct SubscribeTransaction {
+    pub id: String,
+    /// Business of the subscribing session, only business sessions may subscribe
+    pub business_id: Option<Uuid>,
+    pub transaction_id: String,
+    pub subscribe: bool,
+}
+
+impl Handler<SubscribeTransaction> for Server {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: SubscribeTransaction, _: &mut Context<Self>) -> Self::Result {
+        if msg.subscribe {
+            if msg.business_id.is_none() {
+                return Err("Only sessions of a business can subscribe to transactions".to_string());
+            }
+            self.subscribe(&msg.id, &msg.transaction_id)
         } else {
-            self.send_message_to_all(message_str);
+            self.unsubscribe(&msg.id, &msg.transaction_id);
+            Ok(())
+        }
+    }
+}
+
+impl Handler<ClientAck> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: ClientAck, _: &mut Context<Self>) -> Self::Result {
+        let delivered_to_sender = self
+            .pending_acks
+            .get(&msg.message_id)
+            .is_some_and(|pending| pending.receivers.contains(&msg.conn_id));
+        if !delivered_to_sender {
+            warn!(
+                "Ignoring ack of message {} from connection {} it wasn't sent to",
+                msg.message_id, msg.conn_id
+            );
+            return;
+        }
+        if let Some(pending) = self.pending_acks.remove(&msg.message_id) {
+            let _ = pending.sender.send(());
         }
     }
 }
 
+/// Bytes of the character once escaped in a JSON string.
+fn escaped_len(c: char) -> usize {
+    match c {
+        '"' | '\\' | '\n' | '\r' | '\t' | '\u{08}' | '\u{0c}' => 2,
+        c if c < ' ' => 6,
+        c => c.len_utf8(),
+    }
+}
+
+/// Splits text into parts of at most `max` bytes once escaped as a JSON string, without cutting
+/// a character.
+fn split_chunks(text: &str, max: usize) -> Vec<&str> {
+    let mut chunks = vec![];
+    let (mut start, mut size) = (0, 0);
+    for (i, c) in text.char_indices() {
+        let len = escaped_len(c);
+        if size + len > max && i > start {
+            chunks.push(&text[start..i]);
+            (start, size) = (i, 0);
+        }
+        size += len;
+    }
+    if start < text.len() {
+        chunks.push(&text[start..]);
+    }
+    chunks
+}
+
+/// Serialized `chunk` frames of a message, each at most `max_frame_bytes` long.
+fn chunk_frames(text: &str, max_frame_bytes: usize) -> Vec<String> {
+    let chunk_id = Uuid::new_v4();
+    let message_id = PayloadMeta::parse(text).message_id;
+    let frame = |index, total, data: &str| ServerFrame::Chunk {
+        chunk_id,
+        message_id,
+        index,
+        total,
+        data: data.to_string(),
+    };
+    // the envelope with the widest index and total, `data` gets the rest
+    let envelope = to_string(&frame(usize::MAX, usize::MAX, "")).unwrap().len();
+    let chunks = split_chunks(text, max_frame_bytes.saturating_sub(envelope));
+    let total = chunks.len();
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, data)| to_string(&frame(index, total, data)).unwrap())
+        .collect()
+}
+
 pub struct WebSocketSession {
     id: String,
+    conn_id: Uuid,
+    user_id: Option<Uuid>,
+    business_id: Option<Uuid>,
     hb: Instant,
+    last_seen: Option<u64>,
+    client: ClientInfo,
+    max_frame_bytes: usize,
     server_addr: Addr<Server>,
+    inbound_publisher: InboundPublisher,
 }
 
 impl WebSocketSession {
-    pub fn new(key: String, server_addr: Addr<Server>) -> Self {
+    pub fn new(
+        param: &WebSocketParam,
+        client: ClientInfo,
+        max_frame_bytes: usize,
+        server_addr: Addr<Server>,
+        inbound_publisher: InboundPublisher,
+    ) -> Self {
         Self {
-            id: key,
+            id: param.get_ws_key(),
+            conn_id: Uuid::new_v4(),
+            user_id: param.user_id,
+            business_id: param.business_id,
             hb: Instant::now(),
+            last_seen: param.last_seen,
+            client,
+            max_frame_bytes,
             server_addr,
+            inbound_publisher,
+        }
+    }
+
+    fn report(&self, activity: Activity) {
+        self.server_addr.do_send(SessionActivity {
+            conn_id: self.conn_id,
+            activity,
+        });
+    }
+
+    /// Sends a message as one text frame, or as `chunk` frames when it is above the frame limit
+    /// so clients don't stall on a multi-megabyte frame, e.g. an `on_search` catalog.
+    fn send_text(&self, ctx: &mut <Self as Actor>::Context, text: String) {
+        if text.len() <= self.max_frame_bytes {
+            return ctx.text(text);
         }
+        for frame in chunk_frames(&text, self.max_frame_bytes) {
+            ctx.text(frame);
+        }
+    }
+
+    fn send_frame(ctx: &mut <Self as Actor>::Context, frame: &ServerFrame) {
+        match to_string(frame) {
+            Ok(data) => ctx.text(data),
+            Err(err) => error!("Frame did not convert to string {:?}", err),
+        }
+    }
+
+    /// Validates a client action and publishes it on the inbound topic.
+    fn handle_action(
+        &self,
+        action_type: WebSocketActionType,
+        data: Value,
+        correlation_id: Option<Uuid>,
+    ) -> Result<Uuid, String> {
+        if !action_type.is_inbound() {
+            return Err(format!("{:?} cannot be sent by a client", action_type));
+        }
+        if !data.is_object() {
+            return Err("data must be a JSON object".to_string());
+        }
+        let correlation_id = correlation_id.unwrap_or_else(Uuid::new_v4);
+        let inbound = InboundMessage {
+            ws_key: self.id.clone(),
+            correlation_id,
+            action_type,
+            data,
+        };
+        let data = to_string(&inbound).map_err(|e| e.to_string())?;
+        self.inbound_publisher.try_publish(MessageData {
+            data,
+            partition_key: self.id.clone(),
+            deliver_at: None,
+            control: false,
+        })?;
+        Ok(correlation_id)
+    }
+
+    /// Forwards a subscription frame and answers it once `Server` has handled it.
+    fn handle_subscription(
+        &self,
+        transaction_id: String,
+        subscribe: bool,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        if transaction_id.trim().is_empty() {
+            return Self::send_frame(
+                ctx,
+                &ServerFrame::Error {
+                    correlation_id: None,
+                    message: "transactionId must not be empty".to_string(),
+                },
+            );
+        }
+        self.server_addr
+            .send(SubscribeTransaction {
+                id: self.id.clone(),
+                business_id: self.business_id,
+                transaction_id: transaction_id.clone(),
+                subscribe,
+            })
+            .into_actor(self)
+            .then(move |res, _act, ctx| {
+                let frame = match res {
+                    Ok(Ok(_)) if subscribe => ServerFrame::Subscribed { transaction_id },
+                    Ok(Ok(_)) => ServerFrame::Unsubscribed { transaction_id },
+                    Ok(Err(message)) => ServerFrame::Error {
+                        correlation_id: None,
+                        message,
+                    },
+                    Err(err) => ServerFrame::Error {
+                        correlation_id: None,
+                        message: err.to_string(),
+                    },
+                };
+                Self::send_frame(ctx, &frame);
+                fut::ready(())
+            })
+            .spawn(ctx);
     }
 
     fn send_heartbeat(&self, ctx: &mut <Self as Actor>::Context) {
         ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
             if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
                 info!("Websocket Client heartbeat failed, disconnecting!");
-                act.server_addr.do_send(Disconnect { id: act.id.clone() });
-                // stop actor
+                // stop actor, `stopping` deregisters the connection
                 ctx.stop();
 
                 // don't try to send a ping
@@ -208,8 +1188,14 @@ impl Actor for WebSocketSession {
         let session_addr = ctx.address();
         self.server_addr
             .send(Connect {
-                addr: session_addr.recipient(),
+                addr: session_addr.clone().recipient(),
                 id: self.id.clone(),
+                conn_id: self.conn_id,
+                user_id: self.user_id,
+                business_id: self.business_id,
+                last_seen: self.last_seen,
+                client: self.client.clone(),
+                closer: session_addr.clone().recipient(),
             })
             .into_actor(self)
             .then(|res, _act, ctx| {
@@ -221,13 +1207,33 @@ impl Actor for WebSocketSession {
             })
             .wait(ctx);
     }
+
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        self.server_addr.do_send(Disconnect {
+            id: self.id.clone(),
+            conn_id: self.conn_id,
+            user_id: self.user_id,
+            business_id: self.business_id,
+        });
+        Running::Stop
+    }
 }
 
 impl Handler<Message> for WebSocketSession {
     type Result = ();
 
     fn handle(&mut self, msg: Message, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+        self.send_text(ctx, msg.0);
+    }
+}
+
+impl Handler<CloseSession> for WebSocketSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: CloseSession, ctx: &mut Self::Context) {
+        info!("Closing ws session: {:?}", msg.0);
+        ctx.close(Some(msg.0));
+        ctx.stop();
     }
 }
 
@@ -236,25 +1242,58 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocketSession
         match msg {
             Ok(ws::Message::Ping(msg)) => {
                 self.hb = Instant::now();
+                self.report(Activity::Heartbeat);
                 ctx.pong(&msg);
             }
             Ok(ws::Message::Pong(_)) => {
                 self.hb = Instant::now();
+                self.report(Activity::Heartbeat);
             }
             Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
             Ok(ws::Message::Close(reason)) => {
                 info!("closed ws session");
-                self.server_addr.do_send(Disconnect {
-                    id: self.id.clone(),
-                });
                 ctx.close(reason);
                 ctx.stop();
             }
             Ok(ws::Message::Text(text)) => {
                 // Handle incoming text messages from the user
                 info!("Received text message: {}", text);
-                // You can process the text message here and optionally send a response
-                ctx.text(format!("Echo: {}", text)); // Echo the message back to the client
+                self.report(Activity::FrameReceived);
+                match serde_json::from_str::<ClientFrame>(&text) {
+                    Ok(ClientFrame::Ack { message_id }) => {
+                        self.server_addr.do_send(ClientAck {
+                            conn_id: self.conn_id,
+                            message_id,
+                        });
+                    }
+                    Ok(ClientFrame::Action {
+                        action_type,
+                        data,
+                        correlation_id,
+                    }) => {
+                        let frame = match self.handle_action(action_type, data, correlation_id) {
+                            Ok(correlation_id) => ServerFrame::ActionAccepted { correlation_id },
+                            Err(message) => ServerFrame::Error {
+                                correlation_id,
+                                message,
+                            },
+                        };
+                        Self::send_frame(ctx, &frame);
+                    }
+                    Ok(ClientFrame::Subscribe { transaction_id }) => {
+                        self.handle_subscription(transaction_id, true, ctx);
+                    }
+                    Ok(ClientFrame::Unsubscribe { transaction_id }) => {
+                        self.handle_subscription(transaction_id, false, ctx);
+                    }
+                    Err(err) => Self::send_frame(
+                        ctx,
+                        &ServerFrame::Error {
+                            correlation_id: None,
+                            message: format!("Invalid frame: {}", err),
+                        },
+                    ),
+                }
             }
             Err(err) => {
                 warn!("Error handling msg: {:?}", err);
@@ -264,3 +1303,76 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocketSession
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{chunk_frames, split_chunks};
+    use serde_json::{json, Value};
+
+    #[test]
+    fn chunks_fit_the_limit_and_join_back() {
+        let text = "abcdefghij";
+        let chunks = split_chunks(text, 4);
+        assert_eq!(chunks, vec!["abcd", "efgh", "ij"]);
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn exact_multiple_has_no_empty_chunk() {
+        assert_eq!(split_chunks("abcdef", 3), vec!["abc", "def"]);
+        assert_eq!(split_chunks("abc", 3), vec!["abc"]);
+        assert!(split_chunks("", 3).is_empty());
+    }
+
+    #[test]
+    fn never_cuts_a_character() {
+        // "€" is 3 bytes, "😀" is 4
+        let text = "a€€b😀c";
+        for max in 4..=text.len() {
+            let chunks = split_chunks(text, max);
+            assert!(chunks
+                .iter()
+                .all(|chunk| chunk.len() <= max && !chunk.is_empty()));
+            assert_eq!(chunks.concat(), text);
+        }
+        assert_eq!(split_chunks("€€€", 5), vec!["€", "€", "€"]);
+        assert_eq!(split_chunks("a😀", 4), vec!["a", "😀"]);
+    }
+
+    #[test]
+    fn escaped_characters_count_at_their_escaped_size() {
+        // `"` and `\` take 2 bytes escaped, control characters 2 or 6
+        assert_eq!(split_chunks(r#""""""#, 4), vec![r#""""#, r#""""#]);
+        assert_eq!(split_chunks("a\\b", 2), vec!["a", "\\", "b"]);
+        assert_eq!(split_chunks("\u{1}\n", 7), vec!["\u{1}", "\n"]);
+    }
+
+    #[test]
+    fn chunk_frames_fit_the_limit_with_quote_dense_json() {
+        let items: Vec<Value> = (0..2000)
+            .map(|i| json!({"id": i, "descriptor": {"name": "\"q\"", "short_desc": "\\\""}}))
+            .collect();
+        let text = json!({
+            "id": "user#business#device",
+            "messageId": "6f0c4f43-6f3b-4bb8-9a43-4a2f3d1f8e10",
+            "data": {"catalog": {"items": items}},
+        })
+        .to_string();
+        let max = 1024;
+
+        let frames = chunk_frames(&text, max);
+
+        assert!(frames.len() > 1);
+        assert!(frames.iter().all(|frame| frame.len() <= max));
+        let data: String = frames
+            .iter()
+            .map(|frame| {
+                let frame: Value = serde_json::from_str(frame).unwrap();
+                assert_eq!(frame["type"], "chunk");
+                assert_eq!(frame["total"], frames.len());
+                frame["data"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(data, text);
+    }
+}